@@ -0,0 +1,593 @@
+//! Derive macros for the `Decodable` and `Encodable` traits of `bencode`.
+//!
+//! A `struct` with named fields is encoded as a dict whose keys are the
+//! field names. Fields are read and written in lexicographical order of
+//! their keys, regardless of the order in which they are declared.
+//! A tuple `struct` with a single field is encoded as that field.
+//!
+//! An `enum` variant without data is encoded as a string containing the
+//! variant name. A variant with a single unnamed field or with named fields
+//! is encoded as a dict mapping the variant name to its data.
+//!
+//! ```ignore
+//! #[macro_use] extern crate bencode_derive;
+//!
+//! #[derive(Decodable, Encodable)]
+//! struct File {
+//!     length: u64,
+//!     #[bencode(bytes)]
+//!     md5sum: Option<Vec<u8>>,
+//!     #[bencode(default)]
+//!     path: Vec<String>,
+//! }
+//! ```
+//!
+//! # Attributes
+//!
+//! Fields accept the following attributes:
+//!
+//! * `#[bencode(rename = "name")]` uses the given name as the dict key.
+//! * `#[bencode(default)]` uses `Default::default()` if the field is missing.
+//! * `#[bencode(default = "path")]` calls the given function if the field
+//!   is missing.
+//! * `#[bencode(bytes)]` encodes a `Vec<u8>` field as a byte string
+//!   rather than a list of integers.
+//! * `#[bencode(rest)]`, on a field of type `BTreeMap<ByteString, Value>`,
+//!   captures any fields not otherwise decoded and writes them back
+//!   when encoding.
+//!
+//! Fields of type `Option<T>` are optional: `None` is not written and a
+//! missing field is decoded as `None`. Such fields may not have
+//! the `default` attribute.
+//!
+//! ```compile_fail
+//! #[macro_use] extern crate bencode_derive;
+//! extern crate bencode;
+//!
+//! #[derive(Decodable)]
+//! struct Foo {
+//!     #[bencode(default)]
+//!     foo: Option<u32>,
+//! }
+//! # fn main() {}
+//! ```
+//!
+//! Variants accept `#[bencode(rename = "name")]`.
+//!
+//! A type with a lifetime parameter implements `DecodableRef` for that
+//! lifetime rather than `Decodable`, allowing fields to borrow from the
+//! decoded data.
+
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use] extern crate quote;
+#[macro_use] extern crate syn;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as Tokens};
+use syn::{Attribute, Data, DeriveInput, ExprPath, Fields, GenericArgument,
+    Generics, Ident, LitStr, PathArguments, Type};
+
+/// Converts an error into `compile_error!` invocations.
+///
+/// `syn::Error::into_compile_error` refers to `::core`, which cannot be
+/// resolved within a crate using the 2015 edition.
+fn compile_error(e: syn::Error) -> Tokens {
+    e.into_iter().map(|e| {
+        let msg = e.to_string();
+        quote_spanned!(e.span()=> compile_error!(#msg);)
+    }).collect()
+}
+
+/// Derives an implementation of `Decodable`, or `DecodableRef` for types
+/// with a lifetime parameter.
+#[proc_macro_derive(Decodable, attributes(bencode))]
+pub fn derive_decodable(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    expand_decodable(&input)
+        .unwrap_or_else(compile_error)
+        .into()
+}
+
+/// Derives an implementation of `Encodable`.
+#[proc_macro_derive(Encodable, attributes(bencode))]
+pub fn derive_encodable(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    expand_encodable(&input)
+        .unwrap_or_else(compile_error)
+        .into()
+}
+
+/// Attributes given in `#[bencode(...)]`
+#[derive(Default)]
+struct Attrs {
+    rename: Option<String>,
+    default: Option<DefaultAttr>,
+    bytes: bool,
+    rest: bool,
+}
+
+enum DefaultAttr {
+    Trait,
+    Path(ExprPath),
+}
+
+/// Named field of a `struct` or `enum` variant
+struct Field {
+    ident: Ident,
+    binding: Ident,
+    key: String,
+    ty: Type,
+    option: bool,
+    default: Option<DefaultAttr>,
+    bytes: bool,
+}
+
+fn parse_attrs(attrs: &[Attribute]) -> syn::Result<Attrs> {
+    let mut res = Attrs::default();
+
+    for attr in attrs {
+        if !attr.path().is_ident("bencode") {
+            continue;
+        }
+
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                let s: LitStr = meta.value()?.parse()?;
+                res.rename = Some(s.value());
+            } else if meta.path.is_ident("default") {
+                if meta.input.peek(Token![=]) {
+                    let s: LitStr = meta.value()?.parse()?;
+                    res.default = Some(DefaultAttr::Path(s.parse()?));
+                } else {
+                    res.default = Some(DefaultAttr::Trait);
+                }
+            } else if meta.path.is_ident("bytes") {
+                res.bytes = true;
+            } else if meta.path.is_ident("rest") {
+                res.rest = true;
+            } else {
+                return Err(meta.error("unknown bencode attribute"));
+            }
+            Ok(())
+        })?;
+    }
+
+    Ok(res)
+}
+
+/// Returns an error if any attribute other than `rename` was given.
+fn check_rename_only(attrs: &Attrs, span: Span) -> syn::Result<()> {
+    if attrs.default.is_some() || attrs.bytes || attrs.rest {
+        Err(syn::Error::new(span, "only `rename` is supported here"))
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns the inner type of `Option<T>`, if `ty` is an `Option`.
+fn option_type(ty: &Type) -> Option<&Type> {
+    let path = match *ty {
+        Type::Path(ref p) if p.qself.is_none() => &p.path,
+        _ => return None
+    };
+
+    let seg = path.segments.last()?;
+
+    if seg.ident != "Option" {
+        return None;
+    }
+
+    match seg.arguments {
+        PathArguments::AngleBracketed(ref args) if args.args.len() == 1 => {
+            match args.args[0] {
+                GenericArgument::Type(ref t) => Some(t),
+                _ => None
+            }
+        }
+        _ => None
+    }
+}
+
+/// Named fields of a `struct` or `enum` variant
+struct Struct {
+    /// Fields, sorted by key
+    fields: Vec<Field>,
+    /// Field capturing additional fields
+    rest: Option<Ident>,
+}
+
+/// Parses named fields, returning them sorted by key.
+fn parse_fields(fields: &syn::FieldsNamed) -> syn::Result<Struct> {
+    let mut res = Vec::with_capacity(fields.named.len());
+    let mut rest = None;
+
+    for (i, field) in fields.named.iter().enumerate() {
+        let attrs = parse_attrs(&field.attrs)?;
+        let ident = field.ident.clone().expect("named field");
+
+        if attrs.rest {
+            if rest.is_some() {
+                return Err(syn::Error::new(ident.span(),
+                    "only one field may have the `rest` attribute"));
+            }
+            if attrs.rename.is_some() || attrs.default.is_some() || attrs.bytes {
+                return Err(syn::Error::new(ident.span(),
+                    "`rest` may not be combined with other attributes"));
+            }
+            rest = Some(ident);
+            continue;
+        }
+
+        let option = option_type(&field.ty).is_some();
+
+        if option && attrs.default.is_some() {
+            return Err(syn::Error::new_spanned(&field.ty,
+                "`default` has no effect on Option fields"));
+        }
+
+        let key = attrs.rename.unwrap_or_else(|| ident.to_string());
+
+        res.push(Field{
+            binding: Ident::new(&format!("__f{}", i), Span::call_site()),
+            key,
+            ty: field.ty.clone(),
+            option,
+            default: attrs.default,
+            bytes: attrs.bytes,
+            ident,
+        });
+    }
+
+    res.sort_by(|a, b| a.key.cmp(&b.key));
+
+    for pair in res.windows(2) {
+        if pair[0].key == pair[1].key {
+            return Err(syn::Error::new(pair[1].ident.span(),
+                format!("duplicate field key {:?}", pair[1].key)));
+        }
+    }
+
+    Ok(Struct{fields: res, rest})
+}
+
+/// Parses the attributes of a single unnamed field,
+/// returning whether it is encoded as a byte string.
+fn parse_newtype(fields: &syn::FieldsUnnamed, span: Span) -> syn::Result<bool> {
+    if fields.unnamed.len() != 1 {
+        return Err(syn::Error::new(span,
+            "tuple types with more than one field are not supported"));
+    }
+
+    let attrs = parse_attrs(&fields.unnamed[0].attrs)?;
+
+    if attrs.rename.is_some() || attrs.default.is_some() || attrs.rest {
+        return Err(syn::Error::new(span,
+            "only `bytes` is supported on unnamed fields"));
+    }
+
+    Ok(attrs.bytes)
+}
+
+/// Returns the name by which a variant is encoded.
+fn variant_name(variant: &syn::Variant) -> syn::Result<String> {
+    let attrs = parse_attrs(&variant.attrs)?;
+    check_rename_only(&attrs, variant.ident.span())?;
+    Ok(attrs.rename.unwrap_or_else(|| variant.ident.to_string()))
+}
+
+fn expand_decodable(input: &DeriveInput) -> syn::Result<Tokens> {
+    let name = &input.ident;
+
+    let body = match input.data {
+        Data::Struct(ref data) => match data.fields {
+            Fields::Named(ref fields) => {
+                read_struct(&parse_fields(fields)?, &quote!(#name))
+            }
+            Fields::Unnamed(ref fields) => {
+                let bytes = parse_newtype(fields, name.span())?;
+                let value = read_value(bytes);
+                quote!(Ok(#name(#value)))
+            }
+            Fields::Unit => return Err(syn::Error::new(name.span(),
+                "unit structs are not supported"))
+        },
+        Data::Enum(ref data) => {
+            let mut arms = Vec::with_capacity(data.variants.len());
+
+            for variant in &data.variants {
+                let ident = &variant.ident;
+                let key = variant_name(variant)?;
+
+                arms.push(match variant.fields {
+                    Fields::Named(ref fields) => {
+                        let read = read_struct(&parse_fields(fields)?, &quote!(#name::#ident));
+                        quote!((#key, true) => Some(#read?),)
+                    }
+                    Fields::Unnamed(ref fields) => {
+                        let bytes = parse_newtype(fields, ident.span())?;
+                        let value = read_value(bytes);
+                        quote!((#key, true) => Some(#name::#ident(#value)),)
+                    }
+                    Fields::Unit => quote!((#key, false) => Some(#name::#ident),)
+                });
+            }
+
+            if arms.is_empty() {
+                quote!(__d.read_variant(|_, _, _| Ok(None)))
+            } else {
+                quote!{
+                    __d.read_variant(|__d, __name, __data| {
+                        Ok(match (__name, __data) {
+                            #( #arms )*
+                            _ => None
+                        })
+                    })
+                }
+            }
+        }
+        Data::Union(_) => return Err(syn::Error::new(name.span(),
+            "unions are not supported"))
+    };
+
+    let mut lifetimes = input.generics.lifetimes();
+    let lifetime = lifetimes.next().map(|l| l.lifetime.clone());
+
+    if let Some(l) = lifetimes.next() {
+        return Err(syn::Error::new(l.lifetime.span(),
+            "types with more than one lifetime parameter are not supported"));
+    }
+
+    let generics = match lifetime {
+        Some(ref l) => add_bounds(&input.generics,
+            quote!(::bencode::DecodableRef<#l>)),
+        None => add_bounds(&input.generics, quote!(::bencode::Decodable))
+    };
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    Ok(match lifetime {
+        Some(l) => quote!{
+            #[automatically_derived]
+            impl #impl_generics ::bencode::DecodableRef<#l> for #name #ty_generics
+                    #where_clause {
+                fn decode_ref(__d: &mut ::bencode::Decoder<#l>)
+                        -> ::std::result::Result<Self, ::bencode::DecodeError> {
+                    #body
+                }
+            }
+        },
+        None => quote!{
+            #[automatically_derived]
+            impl #impl_generics ::bencode::Decodable for #name #ty_generics
+                    #where_clause {
+                fn decode(__d: &mut ::bencode::Decoder)
+                        -> ::std::result::Result<Self, ::bencode::DecodeError> {
+                    #body
+                }
+            }
+        }
+    })
+}
+
+/// Generates code reading each field, in order, and constructing a value
+/// from them.
+fn read_struct(s: &Struct, path: &Tokens) -> Tokens {
+    let reads = s.fields.iter().map(|f| {
+        let binding = &f.binding;
+        let key = &f.key;
+        let ty = &f.ty;
+
+        let option = if f.bytes {
+            quote!(__d.read_option::<::bencode::ByteString>(#key)?
+                .map(|__b| ::std::convert::From::from(__b.0)))
+        } else {
+            quote!(__d.read_option(#key)?)
+        };
+
+        let value = if f.option {
+            option
+        } else {
+            match f.default {
+                Some(DefaultAttr::Trait) => quote!(#option.unwrap_or_default()),
+                Some(DefaultAttr::Path(ref p)) => quote!(#option.unwrap_or_else(#p)),
+                None if f.bytes => quote!(::std::convert::From::from(
+                    __d.read_field::<::bencode::ByteString>(#key)?.0)),
+                None => quote!(__d.read_field(#key)?)
+            }
+        };
+
+        quote!(let #binding: #ty = #value;)
+    });
+
+    let inits = s.fields.iter().map(|f| {
+        let ident = &f.ident;
+        let binding = &f.binding;
+        quote!(#ident: #binding)
+    }).collect::<Vec<_>>();
+
+    match s.rest {
+        Some(ref rest) => {
+            let bindings = s.fields.iter().map(|f| &f.binding).collect::<Vec<_>>();
+
+            quote!{
+                __d.read_struct_with_rest(|__d| {
+                    #( #reads )*
+                    Ok(( #( #bindings, )* ))
+                }).map(|(( #( #bindings, )* ), __rest)| #path{ #( #inits, )* #rest: __rest })
+            }
+        }
+        None => quote!{
+            __d.read_struct(|__d| {
+                #( #reads )*
+                Ok(#path{ #( #inits ),* })
+            })
+        }
+    }
+}
+
+/// Generates code decoding a single value.
+fn read_value(bytes: bool) -> Tokens {
+    if bytes {
+        quote!(::std::convert::From::from(
+            <::bencode::ByteString as ::bencode::Decodable>::decode(__d)?.0))
+    } else {
+        quote!(::bencode::DecodableRef::decode_ref(__d)?)
+    }
+}
+
+fn expand_encodable(input: &DeriveInput) -> syn::Result<Tokens> {
+    let name = &input.ident;
+
+    let body = match input.data {
+        Data::Struct(ref data) => match data.fields {
+            Fields::Named(ref fields) => {
+                let s = parse_fields(fields)?;
+                let values = s.fields.iter().map(|f| {
+                    let ident = &f.ident;
+                    quote!(&self.#ident)
+                }).collect::<Vec<_>>();
+                let rest = s.rest.as_ref().map(|rest| quote!(&self.#rest));
+                write_struct(&s, &values, rest)
+            }
+            Fields::Unnamed(ref fields) => {
+                let bytes = parse_newtype(fields, name.span())?;
+                write_value(bytes, &quote!(&self.0))
+            }
+            Fields::Unit => return Err(syn::Error::new(name.span(),
+                "unit structs are not supported"))
+        },
+        Data::Enum(ref data) => {
+            let mut arms = Vec::with_capacity(data.variants.len());
+
+            for variant in &data.variants {
+                let ident = &variant.ident;
+                let key = variant_name(variant)?;
+
+                arms.push(match variant.fields {
+                    Fields::Named(ref fields) => {
+                        let s = parse_fields(fields)?;
+                        let values = s.fields.iter().map(|f| {
+                            let binding = &f.binding;
+                            quote!(#binding)
+                        }).collect::<Vec<_>>();
+                        let mut bindings = s.fields.iter().map(|f| {
+                            let ident = &f.ident;
+                            let binding = &f.binding;
+                            quote!(#ident: ref #binding)
+                        }).collect::<Vec<_>>();
+                        if let Some(ref rest) = s.rest {
+                            bindings.push(quote!(#rest: ref __rest));
+                        }
+                        let write = write_struct(&s, &values,
+                            s.rest.as_ref().map(|_| quote!(__rest)));
+
+                        quote!{
+                            #name::#ident{ #( #bindings ),* } => __e.write_struct(|__e| {
+                                __e.write_str(#key)?;
+                                #write
+                            }),
+                        }
+                    }
+                    Fields::Unnamed(ref fields) => {
+                        let bytes = parse_newtype(fields, ident.span())?;
+                        let write = write_value(bytes, &quote!(__f0));
+
+                        quote!{
+                            #name::#ident(ref __f0) => __e.write_struct(|__e| {
+                                __e.write_str(#key)?;
+                                #write
+                            }),
+                        }
+                    }
+                    Fields::Unit => quote!(#name::#ident => __e.write_str(#key),)
+                });
+            }
+
+            quote!(match *self { #( #arms )* })
+        }
+        Data::Union(_) => return Err(syn::Error::new(name.span(),
+            "unions are not supported"))
+    };
+
+    let generics = add_bounds(&input.generics, quote!(::bencode::Encodable));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    Ok(quote!{
+        #[automatically_derived]
+        impl #impl_generics ::bencode::Encodable for #name #ty_generics #where_clause {
+            fn encode(&self, __e: &mut ::bencode::Encoder)
+                    -> ::std::result::Result<(), ::bencode::EncodeError> {
+                #body
+            }
+        }
+    })
+}
+
+/// Generates code writing each field.
+///
+/// `values` contains, for each field, an expression referencing its value
+/// and `rest`, if any, references the additional fields.
+fn write_struct(s: &Struct, values: &[Tokens], rest: Option<Tokens>) -> Tokens {
+    let writes = s.fields.iter().zip(values).map(|(f, value)| {
+        let key = &f.key;
+
+        if f.option {
+            let inner = if f.bytes {
+                quote!(::bencode::ByteStr::from_bytes(__v))
+            } else {
+                quote!(__v)
+            };
+
+            quote!{
+                if let Some(ref __v) = *#value {
+                    __e.write_field(#key, #inner)?;
+                }
+            }
+        } else if f.bytes {
+            quote!(__e.write_field(#key, ::bencode::ByteStr::from_bytes(#value))?;)
+        } else {
+            quote!(__e.write_field(#key, #value)?;)
+        }
+    });
+
+    match rest {
+        Some(rest) => quote!{
+            __e.write_struct_with_rest(#rest, |__e| {
+                #( #writes )*
+                Ok(())
+            })
+        },
+        None => quote!{
+            __e.write_struct(|__e| {
+                #( #writes )*
+                Ok(())
+            })
+        }
+    }
+}
+
+/// Generates code encoding a single value, given an expression referencing it.
+fn write_value(bytes: bool, value: &Tokens) -> Tokens {
+    if bytes {
+        quote!(__e.write_bytes(#value))
+    } else {
+        quote!(::bencode::Encodable::encode(#value, __e))
+    }
+}
+
+/// Returns `generics` with the given bound added to each type parameter.
+fn add_bounds(generics: &Generics, bound: Tokens) -> Generics {
+    let mut generics = generics.clone();
+    let params = generics.type_params()
+        .map(|p| p.ident.clone()).collect::<Vec<_>>();
+
+    let where_clause = generics.make_where_clause();
+
+    for param in params {
+        where_clause.predicates.push(parse_quote!(#param: #bound));
+    }
+
+    generics
+}
@@ -0,0 +1,134 @@
+extern crate bencode;
+#[macro_use] extern crate bencode_derive;
+
+use std::collections::BTreeMap;
+
+use bencode::{decode, decode_ref, encode, ByteString, DecodeErrorKind, PathSegment, Value};
+
+#[derive(Debug, Decodable, Encodable, Eq, PartialEq)]
+struct File {
+    path: Vec<String>,
+    length: u64,
+    #[bencode(bytes)]
+    md5sum: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Decodable, Encodable, Eq, PartialEq)]
+struct Info {
+    #[bencode(rename = "piece length")]
+    piece_length: u32,
+    name: String,
+    #[bencode(bytes)]
+    pieces: Vec<u8>,
+    #[bencode(default)]
+    files: Vec<File>,
+    #[bencode(default = "default_private")]
+    private: u8,
+}
+
+fn default_private() -> u8 { 1 }
+
+#[derive(Debug, Decodable, Encodable, Eq, PartialEq)]
+struct Extensible {
+    name: String,
+    #[bencode(rest)]
+    rest: BTreeMap<ByteString, Value>,
+}
+
+#[derive(Debug, Decodable, Encodable, Eq, PartialEq)]
+struct PeerId(#[bencode(bytes)] Vec<u8>);
+
+#[derive(Debug, Decodable, Encodable, Eq, PartialEq)]
+struct Borrowed<'a, T> {
+    name: &'a str,
+    value: T,
+}
+
+#[derive(Debug, Decodable, Encodable, Eq, PartialEq)]
+enum Message {
+    #[bencode(rename = "ping")]
+    Ping,
+    Have(u32),
+    Request{index: u32, begin: u32, length: u32},
+}
+
+#[test]
+fn test_derive_struct() {
+    let info = Info{
+        piece_length: 16384,
+        name: "foo".to_owned(),
+        pieces: b"\x00\xff".to_vec(),
+        files: vec![File{
+            path: vec!["a".to_owned(), "b".to_owned()],
+            length: 3,
+            md5sum: None,
+        }],
+        private: 0,
+    };
+
+    let bytes = encode(&info).unwrap();
+    assert_eq!(&bytes[..], &b"d5:filesld6:lengthi3e4:pathl1:a1:beee\
+        4:name3:foo12:piece lengthi16384e6:pieces2:\x00\xff7:privatei0ee"[..]);
+    assert_eq!(decode::<Info>(&bytes).unwrap(), info);
+
+    let info = decode::<Info>(b"d4:name3:foo12:piece lengthi1e6:pieces0:e").unwrap();
+    assert_eq!(info.files, []);
+    assert_eq!(info.private, 1);
+
+    let file = decode::<File>(b"d6:lengthi1e6:md5sum2:ab4:pathlee").unwrap();
+    assert_eq!(file.md5sum, Some(b"ab".to_vec()));
+    assert_eq!(encode(&file).unwrap(), b"d6:lengthi1e6:md5sum2:ab4:pathlee");
+
+    let e = decode::<Info>(b"d5:filesld4:pathleee4:name0:e").unwrap_err();
+    assert_eq!(*e.kind(), DecodeErrorKind::MissingField("length".to_owned()));
+    assert_eq!(e.path(), &[
+        PathSegment::Key("files".to_owned()),
+        PathSegment::Index(0),
+    ]);
+
+    assert_eq!(decode::<PeerId>(b"3:abc").unwrap(), PeerId(b"abc".to_vec()));
+    assert_eq!(encode(&PeerId(b"abc".to_vec())).unwrap(), b"3:abc");
+}
+
+#[test]
+fn test_derive_rest() {
+    let bytes = b"d1:ai1e4:name3:foo1:zi2ee";
+    let ext = decode::<Extensible>(bytes).unwrap();
+
+    assert_eq!(ext.name, "foo");
+    assert_eq!(ext.rest.len(), 2);
+    assert_eq!(ext.rest[&b"z"[..]], Value::Integer(2));
+    assert_eq!(encode(&ext).unwrap(), &bytes[..]);
+}
+
+#[test]
+fn test_derive_borrowed() {
+    let bytes = b"d4:name3:foo5:valuei1ee";
+    let b = decode_ref::<Borrowed<i32>>(bytes).unwrap();
+
+    assert_eq!(b, Borrowed{name: "foo", value: 1});
+    assert_eq!(encode(&b).unwrap(), &bytes[..]);
+}
+
+#[test]
+fn test_derive_enum() {
+    let msgs = [
+        (Message::Ping, &b"4:ping"[..]),
+        (Message::Have(3), &b"d4:Havei3ee"[..]),
+        (Message::Request{index: 1, begin: 2, length: 3},
+            &b"d7:Requestd5:begini2e5:indexi1e6:lengthi3eee"[..]),
+    ];
+
+    for &(ref msg, bytes) in &msgs {
+        assert_eq!(encode(msg).unwrap(), bytes);
+        assert_eq!(decode::<Message>(bytes).unwrap(), *msg);
+    }
+
+    let e = decode::<Message>(b"4:Ping").unwrap_err();
+    assert_eq!(*e.kind(), DecodeErrorKind::UnknownVariant("Ping".to_owned()));
+    assert_eq!(e.position(), Some(0));
+
+    let e = decode::<Message>(b"d4:Have3:fooe").unwrap_err();
+    assert_eq!(e.path(), &[PathSegment::Key("Have".to_owned())]);
+    assert_eq!(e.position(), Some(7));
+}
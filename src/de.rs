@@ -0,0 +1,417 @@
+//! Deserialization of `serde` values from the bencode format.
+//!
+//! Byte strings are borrowed from the input where possible.
+//! Integers `0` and `1` may be deserialized as `bool` values.
+
+use std::borrow::Cow;
+use std::fmt::Display;
+use std::io::Read;
+use std::str::from_utf8;
+
+use serde::de::{self, Deserialize, DeserializeOwned, DeserializeSeed,
+    IntoDeserializer, Visitor};
+use serde::de::value::{BorrowedBytesDeserializer, BorrowedStrDeserializer,
+    BytesDeserializer};
+
+use {Decoder, DecodeError, DecodeErrorKind, DictKeys, PathSegment};
+
+/// Deserializes a value from a stream of bytes.
+pub fn from_bytes<'a, T: Deserialize<'a>>(data: &'a [u8]) -> Result<T, DecodeError> {
+    let mut de = Deserializer::new(Decoder::new(data));
+    let res = T::deserialize(&mut de).map_err(|e| e.at(0))?;
+    de.decoder.finish()?;
+    Ok(res)
+}
+
+/// Deserializes a value from a reader.
+///
+/// The reader is consumed until end-of-file; any data following the value
+/// results in an error.
+pub fn from_reader<T: DeserializeOwned, R: Read>(r: R) -> Result<T, DecodeError> {
+    let mut de = Deserializer::new(Decoder::from_reader(r));
+    let res = T::deserialize(&mut de).map_err(|e| e.at(0))?;
+    de.decoder.finish()?;
+    Ok(res)
+}
+
+/// Deserializes `serde` values using a `Decoder`.
+pub struct Deserializer<'a> {
+    decoder: Decoder<'a>,
+}
+
+impl<'a> Deserializer<'a> {
+    /// Constructs a new `Deserializer`, reading from the given `Decoder`.
+    pub fn new(decoder: Decoder<'a>) -> Deserializer<'a> {
+        Deserializer{decoder}
+    }
+
+    /// Consumes the `Deserializer` and returns the inner `Decoder`.
+    pub fn into_inner(self) -> Decoder<'a> {
+        self.decoder
+    }
+
+    /// Deserializes the contents of a list or dict,
+    /// after the opening byte has been read.
+    fn nested<V, F>(&mut self, f: F) -> Result<V, DecodeError>
+            where F: FnOnce(&mut Self) -> Result<V, DecodeError> {
+        self.decoder.enter()?;
+        let res = f(self);
+        self.decoder.leave();
+        let res = res?;
+        self.decoder.expect(b'e')?;
+        Ok(res)
+    }
+}
+
+impl de::Error for DecodeError {
+    fn custom<T: Display>(msg: T) -> DecodeError {
+        DecodeError::new(DecodeErrorKind::Custom(msg.to_string()))
+    }
+
+    fn missing_field(field: &'static str) -> DecodeError {
+        DecodeError::new(DecodeErrorKind::MissingField(field.to_owned()))
+    }
+
+    fn unknown_variant(variant: &str, _expected: &'static [&'static str]) -> DecodeError {
+        DecodeError::new(DecodeErrorKind::UnknownVariant(variant.to_owned()))
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut Deserializer<'de> {
+    type Error = DecodeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DecodeError> {
+        match self.decoder.peek_byte()? {
+            b'd' => {
+                self.decoder.read_byte()?;
+                self.nested(|de| visitor.visit_map(
+                    DictAccess{de, key: None, keys: DictKeys::new()}))
+            }
+            b'i' => {
+                let pos = self.decoder.position();
+                let s = self.decoder.read_big_integer()?;
+
+                if let Ok(i) = s.parse() {
+                    visitor.visit_i64(i)
+                } else if let Ok(u) = s.parse() {
+                    visitor.visit_u64(u)
+                } else if let Ok(i) = s.parse() {
+                    visitor.visit_i128(i)
+                } else if let Ok(u) = s.parse() {
+                    visitor.visit_u128(u)
+                } else {
+                    Err(DecodeError::new(DecodeErrorKind::InvalidNumber).at(pos + 1))
+                }
+            }
+            b'l' => {
+                self.decoder.read_byte()?;
+                self.nested(|de| visitor.visit_seq(ListAccess{de, index: 0}))
+            }
+            b'0' ..= b'9' => match self.decoder.read_bytes_cow()? {
+                Cow::Borrowed(b) => match from_utf8(b) {
+                    Ok(s) => visitor.visit_borrowed_str(s),
+                    Err(_) => visitor.visit_borrowed_bytes(b)
+                },
+                Cow::Owned(b) => match String::from_utf8(b) {
+                    Ok(s) => visitor.visit_string(s),
+                    Err(e) => visitor.visit_byte_buf(e.into_bytes())
+                }
+            },
+            b => Err(self.decoder.error(DecodeErrorKind::InvalidByte(b)))
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DecodeError> {
+        let pos = self.decoder.position();
+
+        match self.decoder.read_integer::<u8>() {
+            Ok(0) => visitor.visit_bool(false),
+            Ok(1) => visitor.visit_bool(true),
+            _ => Err(DecodeError::new(DecodeErrorKind::InvalidNumber).at(pos))
+        }
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DecodeError> {
+        visitor.visit_u64(self.decoder.read_integer()?)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DecodeError> {
+        match self.decoder.read_bytes_cow()? {
+            Cow::Borrowed(b) => visitor.visit_borrowed_bytes(b),
+            Cow::Owned(b) => visitor.visit_byte_buf(b),
+        }
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DecodeError> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DecodeError> {
+        // Absent values are represented by omitting a field
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V)
+            -> Result<V::Value, DecodeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str,
+            _variants: &'static [&'static str], visitor: V)
+            -> Result<V::Value, DecodeError> {
+        match self.decoder.peek_byte()? {
+            b'd' => {
+                self.decoder.read_byte()?;
+                self.nested(|de| visitor.visit_enum(VariantAccess{de}))
+            }
+            _ => {
+                let s = self.decoder.read_str_cow()?;
+                visitor.visit_enum(IntoDeserializer::<DecodeError>::into_deserializer(&s[..]))
+            }
+        }
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V)
+            -> Result<V::Value, DecodeError> {
+        self.decoder.skip_item()?;
+        visitor.visit_unit()
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    forward_to_deserialize_any!{
+        i8 i16 i32 i64 i128 u8 u16 u32 u128 f32 f64 char str string
+        unit unit_struct seq tuple tuple_struct map struct identifier
+    }
+}
+
+/// Provides the elements of a list.
+struct ListAccess<'x, 'de: 'x> {
+    de: &'x mut Deserializer<'de>,
+    index: usize,
+}
+
+impl<'x, 'de> de::SeqAccess<'de> for ListAccess<'x, 'de> {
+    type Error = DecodeError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T)
+            -> Result<Option<T::Value>, DecodeError> {
+        if self.de.decoder.peek_byte()? == b'e' {
+            return Ok(None);
+        }
+
+        self.de.decoder.count_element()?;
+        let pos = self.de.decoder.position();
+        let index = self.index;
+        self.index += 1;
+
+        seed.deserialize(&mut *self.de).map(Some)
+            .map_err(|e| e.at(pos).within(PathSegment::Index(index)))
+    }
+}
+
+/// Provides the entries of a dict.
+struct DictAccess<'x, 'de: 'x> {
+    de: &'x mut Deserializer<'de>,
+    /// Most recently read key
+    key: Option<Vec<u8>>,
+    /// Keys read
+    keys: DictKeys,
+}
+
+impl<'x, 'de> de::MapAccess<'de> for DictAccess<'x, 'de> {
+    type Error = DecodeError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K)
+            -> Result<Option<K::Value>, DecodeError> {
+        let (pos, key) = loop {
+            if self.de.decoder.peek_byte()? == b'e' {
+                return Ok(None);
+            }
+
+            self.de.decoder.count_element()?;
+            let pos = self.de.decoder.position();
+            let key = self.de.decoder.read_bytes_cow()?;
+
+            // Ensure that this key is greater than the greatest previous key
+            let duplicate = self.keys.contains(&key);
+            self.keys.check(&mut self.de.decoder, &key, pos)?;
+
+            if !duplicate {
+                break (pos, key);
+            }
+
+            // A `Visitor` may not accept a duplicate key,
+            // so the first value is always retained.
+            self.de.decoder.skip_item()?;
+        };
+
+        let res = match key {
+            Cow::Borrowed(b) => match from_utf8(b) {
+                Ok(s) => seed.deserialize(BorrowedStrDeserializer::new(s)),
+                Err(_) => seed.deserialize(BorrowedBytesDeserializer::new(b))
+            },
+            Cow::Owned(ref b) => match from_utf8(b) {
+                Ok(s) => seed.deserialize(IntoDeserializer::into_deserializer(s)),
+                Err(_) => seed.deserialize(BytesDeserializer::new(b))
+            },
+        };
+
+        self.key = Some(key.into_owned());
+        res.map(Some).map_err(|e: DecodeError| e.at(pos))
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V)
+            -> Result<V::Value, DecodeError> {
+        let pos = self.de.decoder.position();
+        let key = &self.key;
+
+        seed.deserialize(&mut *self.de)
+            .map_err(|e| e.at(pos).within(
+                PathSegment::key(key.as_ref().map_or(&[][..], |k| &k[..]))))
+    }
+}
+
+/// Provides the variant of an enum represented as a dict with a single key.
+struct VariantAccess<'x, 'de: 'x> {
+    de: &'x mut Deserializer<'de>,
+}
+
+impl<'x, 'de> de::EnumAccess<'de> for VariantAccess<'x, 'de> {
+    type Error = DecodeError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V)
+            -> Result<(V::Value, Self), DecodeError> {
+        let name = self.de.decoder.read_str_cow()?;
+        let v = seed.deserialize(IntoDeserializer::<DecodeError>::into_deserializer(&name[..]))?;
+        Ok((v, self))
+    }
+}
+
+impl<'x, 'de> de::VariantAccess<'de> for VariantAccess<'x, 'de> {
+    type Error = DecodeError;
+
+    fn unit_variant(self) -> Result<(), DecodeError> {
+        self.de.decoder.skip_item()
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T)
+            -> Result<T::Value, DecodeError> {
+        seed.deserialize(self.de)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V)
+            -> Result<V::Value, DecodeError> {
+        de::Deserializer::deserialize_seq(self.de, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V)
+            -> Result<V::Value, DecodeError> {
+        de::Deserializer::deserialize_map(self.de, visitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use serde::Deserialize;
+    use super::{from_bytes, from_reader, Deserializer};
+    use ser::to_bytes;
+    use {Decoder, DecoderOptions, DecodeErrorKind, PathSegment};
+
+    #[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+    struct Torrent<'a> {
+        name: String,
+        length: u64,
+        comment: Option<String>,
+        private: bool,
+        #[serde(borrow)]
+        tag: &'a str,
+        files: Vec<File>,
+    }
+
+    #[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+    struct File {
+        length: u64,
+        path: Vec<String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Nested(#[allow(dead_code)] Vec<Nested>);
+
+    #[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+    enum Message {
+        Ping,
+        Have(u32),
+        Request{index: u32, begin: u32},
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let bytes = b"d\
+            5:filesld6:lengthi1e4:pathl1:aeee\
+            6:lengthi123e\
+            4:name3:foo\
+            7:privatei1e\
+            3:tag3:bar\
+            7:unknowni0e\
+            e";
+
+        let t: Torrent = from_bytes(bytes).unwrap();
+        assert_eq!(t, Torrent{
+            name: "foo".to_owned(),
+            length: 123,
+            comment: None,
+            private: true,
+            tag: "bar",
+            files: vec![File{length: 1, path: vec!["a".to_owned()]}],
+        });
+
+        let m: BTreeMap<String, i64> = from_reader(&b"d1:ai1e1:bi2ee"[..]).unwrap();
+        assert_eq!(m.len(), 2);
+
+        let big = b"i340282366920938463463374607431768211455e";
+        assert_eq!(from_bytes::<u128>(big).unwrap(), u128::MAX);
+        assert_eq!(to_bytes(&u128::MAX).unwrap(), &big[..]);
+
+        for msg in &[
+                Message::Ping,
+                Message::Have(7),
+                Message::Request{index: 1, begin: 2}] {
+            assert_eq!(from_bytes::<Message>(&to_bytes(msg).unwrap()).unwrap(), *msg);
+        }
+    }
+
+    #[test]
+    fn test_deserialize_errors() {
+        let e = from_bytes::<File>(b"d6:lengthi1e4:pathl1:ai2eee").unwrap_err();
+        assert_eq!(e.path(), &[
+            PathSegment::Key("path".to_owned()),
+            PathSegment::Index(1),
+        ]);
+        assert_eq!(e.position(), Some(22));
+
+        let e = from_bytes::<File>(b"d6:lengthi1ee").unwrap_err();
+        assert_eq!(*e.kind(), DecodeErrorKind::MissingField("path".to_owned()));
+
+        let e = from_bytes::<BTreeMap<String, i64>>(b"d1:bi1e1:ai2ee").unwrap_err();
+        assert_eq!(*e.kind(), DecodeErrorKind::InvalidDict);
+
+        let e = from_bytes::<Nested>(&[b'l'; 1000]).unwrap_err();
+        assert_eq!(*e.kind(), DecodeErrorKind::DepthLimitExceeded);
+    }
+
+    #[test]
+    fn test_deserialize_lenient() {
+        let mut d = Decoder::new(b"d4:pathle6:lengthi01e6:lengthi2ee");
+        d.set_options(DecoderOptions::new().lenient(true));
+
+        let mut de = Deserializer::new(d);
+        let file = File::deserialize(&mut de).unwrap();
+        assert_eq!(file, File{length: 1, path: vec![]});
+        assert_eq!(de.into_inner().warnings().len(), 3);
+    }
+}
@@ -1,17 +1,46 @@
 //! Encoding and decoding for the bencode format.
 
+extern crate digest;
 extern crate sha1;
-
-use std::collections::BTreeMap;
+extern crate sha2;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+
+#[cfg(all(test, feature = "serde"))]
+#[macro_use]
+extern crate serde_derive;
+
+use std::borrow::{Borrow, Cow};
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
 use std::fmt;
-use std::io::{Cursor, Read, Write};
+use std::hash::{self, BuildHasher};
+use std::io::{self, Cursor, Read, Write};
 use std::mem::transmute;
 use std::ops::Deref;
 use std::rc::Rc;
 use std::str::{from_utf8, FromStr};
 use std::sync::Arc;
 
-use sha1::Sha1;
+use digest::{Digest, Output};
+
+pub use sha1::Sha1;
+pub use sha2::Sha256;
+
+#[cfg(feature = "serde")]
+pub use de::{from_bytes, from_reader, Deserializer};
+#[cfg(feature = "serde")]
+pub use ser::{to_bytes, to_writer, Serializer};
+pub use token::{Token, Tokenizer};
+
+#[cfg(feature = "serde")]
+pub mod de;
+#[cfg(feature = "serde")]
+pub mod ser;
+pub mod metainfo;
+pub mod token;
 
 /// Decodes a value from a stream of bytes.
 pub fn decode<T: Decodable>(data: &[u8]) -> Result<T, DecodeError> {
@@ -21,55 +50,569 @@ pub fn decode<T: Decodable>(data: &[u8]) -> Result<T, DecodeError> {
     Ok(res)
 }
 
+/// Decodes a value which may borrow from the given stream of bytes.
+pub fn decode_ref<'a, T: DecodableRef<'a>>(data: &'a [u8]) -> Result<T, DecodeError> {
+    let mut d = Decoder::new(data);
+    let res = DecodableRef::decode_ref(&mut d)?;
+    d.finish()?;
+    Ok(res)
+}
+
+/// Decodes a value from a reader.
+///
+/// The reader is consumed until end-of-file; any data following the value
+/// results in an error. To decode a series of values from a stream,
+/// use `Decoder::from_reader` directly.
+pub fn decode_from<T: Decodable, R: Read>(r: R) -> Result<T, DecodeError> {
+    let mut d = Decoder::from_reader(r);
+    let res = Decodable::decode(&mut d)?;
+    d.finish()?;
+    Ok(res)
+}
+
+/// Checks whether a stream of bytes is a single value in canonical form.
+///
+/// Returns each violation of canonical form, i.e. unsorted or duplicate
+/// dict keys, numbers with leading zeros or negative zero, and data
+/// following the value. An empty list indicates that the data is canonical.
+/// Data which cannot be decoded, even leniently, produces an error.
+pub fn validate_canonical(data: &[u8]) -> Result<Vec<DecodeWarning>, DecodeError> {
+    let mut d = Decoder::new(data);
+    d.set_options(DecoderOptions::new().lenient(true));
+
+    ValueRef::decode_ref(&mut d)?;
+
+    if d.remaining() != 0 {
+        let pos = d.position();
+        d.warn(DecodeWarningKind::ExtraneousData, pos);
+    }
+
+    Ok(d.warnings)
+}
+
+/// Re-encodes a stream of bytes in canonical form.
+///
+/// The value is decoded leniently, as with `validate_canonical`.
+/// Of duplicate dict keys, the last is retained. Any data following
+/// the value is discarded.
+pub fn canonicalize(data: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let mut d = Decoder::new(data);
+    d.set_options(DecoderOptions::new().lenient(true));
+
+    let v = ValueRef::decode_ref(&mut d)?;
+    Ok(encode(&v).expect("encoding to Vec"))
+}
+
 /// Encodes a value into a stream of bytes.
 pub fn encode<T: ?Sized + Encodable>(t: &T) -> Result<Vec<u8>, EncodeError> {
     let mut e = Encoder::new();
     t.encode(&mut e)?;
-    Ok(e.into_bytes())
+    Ok(e.into_bytes().expect("encoding to Vec"))
+}
+
+/// Encodes a value into a writer.
+pub fn encode_to<T: ?Sized + Encodable, W: Write>(w: W, t: &T)
+        -> Result<(), EncodeError> {
+    let mut e = Encoder::from_writer(w);
+    t.encode(&mut e)?;
+    e.flush()
 }
 
+/// Size of each read performed on an `io::Read` source.
+const READ_CHUNK_SIZE: usize = 8192;
+
+/// Maximum number of characters in a number read into a fixed-width integer.
+///
+/// Integers outside this limit read by `read_big_integer` are instead
+/// charged against the allocation limit.
+const MAX_NUMBER_DIGITS: usize = 40;
+
+/// Default maximum nesting depth of lists and dicts.
+pub const DEFAULT_MAX_DEPTH: usize = 256;
+
 /// Decodes values from a stream of bytes.
-#[derive(Clone)]
+///
+/// A `Decoder` reading from a byte string may be copied with
+/// `Decoder::try_clone`, producing an independent cursor over the same input.
 pub struct Decoder<'a> {
-    data: Cursor<&'a [u8]>,
+    data: Source<'a>,
+    options: DecoderOptions,
+    /// Number of lists and dicts currently entered
+    depth: usize,
+    /// Number of list and dict elements decoded
+    elements: usize,
+    /// Number of bytes allocated for byte strings
+    alloc: usize,
+    /// Structs currently being read, innermost last
+    structs: Vec<StructFrame>,
+    /// Fields skipped by `read_struct`, if collected
+    skipped: Vec<SkippedField>,
+    /// Violations accepted in lenient mode
+    warnings: Vec<DecodeWarning>,
+}
+
+/// State of a `struct` being read by `Decoder::read_struct`
+/// or `Decoder::read_struct_indexed`.
+#[derive(Clone)]
+struct StructFrame {
+    /// Fields by key, if the struct is indexed
+    index: Option<BTreeMap<Vec<u8>, IndexedField>>,
+    /// Position of the first key
+    start: u64,
+    /// Fields captured by `read_struct_with_rest`
+    rest: Option<BTreeMap<ByteString, Value>>,
+}
+
+/// Field of a `struct` read by `Decoder::read_struct_indexed`
+#[derive(Clone)]
+struct IndexedField {
+    /// Position of the key
+    key_pos: u64,
+    /// Position of the value
+    value_pos: u64,
+    /// Whether the field has been read
+    read: bool,
+    /// Element and allocation counts before the field was first read
+    counts: (usize, usize),
+}
+
+/// Keys of a dict being read, used to check the order of each new key.
+#[derive(Default)]
+struct DictKeys {
+    /// Keys previously read; in strict mode, only the greatest key is kept
+    keys: BTreeSet<Vec<u8>>,
+}
+
+impl DictKeys {
+    fn new() -> DictKeys {
+        DictKeys::default()
+    }
+
+    /// Returns whether `key` has previously been read.
+    #[cfg(feature = "serde")]
+    fn contains(&self, key: &[u8]) -> bool {
+        self.keys.contains(key)
+    }
+
+    /// Checks that a key at position `pos` is greater than any previous key
+    /// and records it.
+    ///
+    /// In lenient mode, a key out of order produces a warning rather than
+    /// an error. Returns `false` if the key is a duplicate whose value
+    /// should be discarded.
+    fn check(&mut self, d: &mut Decoder, key: &[u8], pos: u64) -> Result<bool, DecodeError> {
+        match self.keys.iter().next_back() {
+            Some(last) if key <= &last[..] => (),
+            _ => {
+                if !d.options.lenient {
+                    // Only the greatest key is needed to check order
+                    self.keys.clear();
+                }
+                self.keys.insert(key.to_vec());
+                return Ok(true);
+            }
+        }
+
+        if !d.options.lenient {
+            return Err(DecodeError::new(DecodeErrorKind::InvalidDict).at(pos));
+        }
+
+        let name = String::from_utf8_lossy(key).into_owned();
+
+        if self.keys.contains(key) {
+            d.warn(DecodeWarningKind::DuplicateKey(name), pos);
+            Ok(d.options.duplicate_keys == DuplicateKeys::Last)
+        } else {
+            d.warn(DecodeWarningKind::UnsortedKey(name), pos);
+            self.keys.insert(key.to_vec());
+            Ok(true)
+        }
+    }
+}
+
+/// Configures the behavior of a `Decoder`,
+/// including limits on the resources it uses.
+///
+/// Each limit, when exceeded, produces a distinct `DecodeErrorKind`.
+/// Apart from the maximum depth, resources are unlimited by default.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DecoderOptions {
+    max_depth: usize,
+    max_bytes_len: usize,
+    max_elements: usize,
+    max_alloc: usize,
+    unknown_fields: UnknownFields,
+    lenient: bool,
+    duplicate_keys: DuplicateKeys,
+}
+
+/// Determines which of duplicate dict keys is retained in lenient mode.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DuplicateKeys {
+    /// The first occurrence of a key is retained
+    First,
+    /// The last occurrence of a key is retained
+    Last,
+}
+
+/// Determines the treatment of fields not read while decoding a `struct`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum UnknownFields {
+    /// Unknown fields are skipped
+    Skip,
+    /// Unknown fields are skipped and recorded;
+    /// see `Decoder::skipped_fields`
+    Collect,
+    /// Unknown fields produce an error of kind `DecodeErrorKind::UnknownField`
+    Deny,
+}
+
+/// Field skipped while decoding a `struct`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SkippedField {
+    key: ByteString,
+    position: u64,
+}
+
+impl SkippedField {
+    /// Returns the key of the field.
+    pub fn key(&self) -> &ByteStr {
+        &self.key
+    }
+
+    /// Returns the position of the key in the stream.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+}
+
+impl DecoderOptions {
+    /// Returns the default options.
+    pub fn new() -> DecoderOptions {
+        DecoderOptions{
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_bytes_len: usize::MAX,
+            max_elements: usize::MAX,
+            max_alloc: usize::MAX,
+            unknown_fields: UnknownFields::Skip,
+            lenient: false,
+            duplicate_keys: DuplicateKeys::Last,
+        }
+    }
+
+    /// Sets the maximum nesting depth of lists and dicts.
+    ///
+    /// Exceeding this limit produces `DecodeErrorKind::DepthLimitExceeded`.
+    /// The default is `DEFAULT_MAX_DEPTH`.
+    pub fn max_depth(mut self, n: usize) -> DecoderOptions {
+        self.max_depth = n;
+        self
+    }
+
+    /// Sets the maximum length of a single byte string.
+    ///
+    /// Exceeding this limit produces `DecodeErrorKind::BytesLimitExceeded`.
+    pub fn max_bytes_len(mut self, n: usize) -> DecoderOptions {
+        self.max_bytes_len = n;
+        self
+    }
+
+    /// Sets the maximum total number of list elements and dict entries.
+    ///
+    /// Exceeding this limit produces `DecodeErrorKind::ElementLimitExceeded`.
+    pub fn max_elements(mut self, n: usize) -> DecoderOptions {
+        self.max_elements = n;
+        self
+    }
+
+    /// Sets the maximum total number of bytes allocated for byte strings
+    /// and the digits of integers of more than 40 characters.
+    ///
+    /// Exceeding this limit produces `DecodeErrorKind::AllocLimitExceeded`.
+    pub fn max_alloc(mut self, n: usize) -> DecoderOptions {
+        self.max_alloc = n;
+        self
+    }
+
+    /// Sets the treatment of fields not read while decoding a `struct`.
+    ///
+    /// `UnknownFields::Deny` enables a strict mode, in which any field
+    /// not read by `read_field` or `read_option` produces an error.
+    /// The default is `UnknownFields::Skip`.
+    pub fn unknown_fields(mut self, unknown: UnknownFields) -> DecoderOptions {
+        self.unknown_fields = unknown;
+        self
+    }
+
+    /// Sets whether to accept data which violates the bencode format
+    /// in common ways.
+    ///
+    /// In lenient mode, dicts with unsorted or duplicate keys and numbers
+    /// with leading zeros or `-0` are accepted, each producing a warning;
+    /// see `Decoder::warnings`. Structs are read as with
+    /// `Decoder::read_struct_indexed`, so that fields may appear in any order.
+    /// The default is `false`.
+    pub fn lenient(mut self, lenient: bool) -> DecoderOptions {
+        self.lenient = lenient;
+        self
+    }
+
+    /// Sets which of duplicate dict keys is retained in lenient mode.
+    ///
+    /// When deserializing with `serde`, the first is always retained.
+    /// The default is `DuplicateKeys::Last`.
+    pub fn duplicate_keys(mut self, keep: DuplicateKeys) -> DecoderOptions {
+        self.duplicate_keys = keep;
+        self
+    }
+}
+
+impl Default for DecoderOptions {
+    fn default() -> DecoderOptions {
+        DecoderOptions::new()
+    }
+}
+
+/// Input consumed by a `Decoder`.
+enum Source<'a> {
+    /// Borrowed byte string
+    Slice(Cursor<&'a [u8]>),
+    /// Buffered reader
+    Reader(ReadBuffer<'a>),
+}
+
+/// Buffers data from an `io::Read` source.
+///
+/// Bytes preceding the cursor are discarded as new data is read,
+/// unless a position has been pinned by the `Decoder`.
+struct ReadBuffer<'a> {
+    reader: Box<dyn Read + 'a>,
+    buf: Vec<u8>,
+    /// Stream position of `buf[0]`
+    start: u64,
+    /// Stream position of the cursor
+    pos: u64,
+    /// Positions which must remain in the buffer
+    pins: Vec<u64>,
+    eof: bool,
+}
+
+impl<'a> ReadBuffer<'a> {
+    /// Returns buffered bytes following the cursor.
+    fn buffered(&self) -> &[u8] {
+        &self.buf[(self.pos - self.start) as usize..]
+    }
+
+    /// Reads from the source until at least `n` bytes following the cursor
+    /// are buffered or end-of-file is reached.
+    /// Returns all buffered bytes following the cursor.
+    fn fill(&mut self, n: usize) -> Result<&[u8], DecodeError> {
+        while self.buffered().len() < n && !self.eof {
+            self.discard();
+
+            let len = self.buf.len();
+            self.buf.resize(len + READ_CHUNK_SIZE, 0);
+
+            match self.reader.read(&mut self.buf[len..]) {
+                Ok(0) => {
+                    self.buf.truncate(len);
+                    self.eof = true;
+                }
+                Ok(n) => self.buf.truncate(len + n),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                    self.buf.truncate(len);
+                }
+                Err(e) => {
+                    self.buf.truncate(len);
+                    return Err(DecodeError::new(DecodeErrorKind::Io(e.kind()))
+                        .at(self.pos));
+                }
+            }
+        }
+
+        Ok(self.buffered())
+    }
+
+    /// Drops buffered bytes preceding both the cursor and any pinned position.
+    fn discard(&mut self) {
+        let keep = self.pins.iter().cloned().fold(self.pos, u64::min);
+        let n = (keep - self.start) as usize;
+
+        if n != 0 {
+            self.buf.drain(..n);
+            self.start = keep;
+        }
+    }
 }
 
 impl<'a> Decoder<'a> {
     /// Constructs a new `Decoder`, reading from the given byte string.
-    pub fn new(data: &[u8]) -> Decoder {
-        Decoder{data: Cursor::new(data)}
+    pub fn new(data: &'a [u8]) -> Decoder<'a> {
+        Decoder::with_source(Source::Slice(Cursor::new(data)))
+    }
+
+    /// Constructs a new `Decoder`, reading from the given reader.
+    ///
+    /// Data is read from the reader incrementally, as it is required.
+    pub fn from_reader<R: Read + 'a>(reader: R) -> Decoder<'a> {
+        Decoder::with_source(Source::Reader(ReadBuffer{
+            reader: Box::new(reader),
+            buf: Vec::new(),
+            start: 0,
+            pos: 0,
+            pins: Vec::new(),
+            eof: false,
+        }))
+    }
+
+    fn with_source(data: Source<'a>) -> Decoder<'a> {
+        Decoder{
+            data,
+            options: DecoderOptions::new(),
+            depth: 0,
+            elements: 0,
+            alloc: 0,
+            structs: Vec::new(),
+            skipped: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Returns a copy of the `Decoder`, with its own cursor over the same
+    /// input, or `None` if the `Decoder` reads from a reader.
+    pub fn try_clone(&self) -> Option<Decoder<'a>> {
+        let data = match self.data {
+            Source::Slice(ref c) => Source::Slice(c.clone()),
+            Source::Reader(_) => return None,
+        };
+
+        Some(Decoder{
+            data,
+            options: self.options,
+            depth: self.depth,
+            elements: self.elements,
+            alloc: self.alloc,
+            structs: self.structs.clone(),
+            skipped: self.skipped.clone(),
+            warnings: self.warnings.clone(),
+        })
+    }
+
+    /// Returns the options of the `Decoder`.
+    pub fn options(&self) -> &DecoderOptions {
+        &self.options
+    }
+
+    /// Sets the options of the `Decoder`.
+    pub fn set_options(&mut self, options: DecoderOptions) {
+        self.options = options;
+    }
+
+    /// Returns the maximum nesting depth of lists and dicts.
+    pub fn max_depth(&self) -> usize {
+        self.options.max_depth
+    }
+
+    /// Sets the maximum nesting depth of lists and dicts.
+    ///
+    /// Decoding a value nested more deeply returns an error of kind
+    /// `DecodeErrorKind::DepthLimitExceeded`.
+    /// The default is `DEFAULT_MAX_DEPTH`.
+    pub fn set_max_depth(&mut self, depth: usize) {
+        self.options.max_depth = depth;
+    }
+
+    /// Returns the fields skipped while decoding a `struct`.
+    ///
+    /// Fields are recorded only when the `unknown_fields` option
+    /// is `UnknownFields::Collect`.
+    pub fn skipped_fields(&self) -> &[SkippedField] {
+        &self.skipped
+    }
+
+    /// Returns the violations of the bencode format accepted in lenient mode.
+    pub fn warnings(&self) -> &[DecodeWarning] {
+        &self.warnings
     }
 
     /// Returns the number of bytes remaining in the stream.
+    ///
+    /// For a `Decoder` constructed with `from_reader`, this is the number
+    /// of bytes which have been read from the reader but not yet decoded.
     pub fn remaining(&self) -> usize {
-        self.data.get_ref().len() - self.data.position() as usize
+        match self.data {
+            Source::Slice(ref c) => c.get_ref().len() - c.position() as usize,
+            Source::Reader(ref r) => r.buffered().len(),
+        }
     }
 
     /// Returns the current position of the cursor.
     pub fn position(&self) -> u64 {
-        self.data.position()
+        match self.data {
+            Source::Slice(ref c) => c.position(),
+            Source::Reader(ref r) => r.pos,
+        }
     }
 
     /// Sets the current position of the cursor.
+    ///
+    /// # Panics
+    ///
+    /// For a `Decoder` constructed with `from_reader`, panics if `pos`
+    /// is not within the data currently buffered.
     pub fn set_position(&mut self, pos: u64) {
-        self.data.set_position(pos);
+        match self.data {
+            Source::Slice(ref mut c) => c.set_position(pos),
+            Source::Reader(ref mut r) => {
+                assert!(pos >= r.start && pos <= r.start + r.buf.len() as u64,
+                    "position {} is not buffered", pos);
+                r.pos = pos;
+            }
+        }
     }
 
     /// Returns an error if there is data remaining in the stream.
-    pub fn finish(self) -> Result<(), DecodeError> {
-        if self.remaining() == 0 {
+    pub fn finish(mut self) -> Result<(), DecodeError> {
+        let empty = match self.data {
+            Source::Slice(_) => self.remaining() == 0,
+            Source::Reader(ref mut r) => r.fill(1)?.is_empty(),
+        };
+
+        if empty {
             Ok(())
         } else {
-            Err(DecodeError::ExtraneousData)
+            Err(self.error(DecodeErrorKind::ExtraneousData))
         }
     }
 
     /// Reads a series of bytes from the stream equal to `buf.len()`.
     /// If fewer bytes are available to read, an error is returned.
     pub fn read(&mut self, buf: &mut [u8]) -> Result<(), DecodeError> {
-        match self.data.read(buf) {
-            Ok(n) if n == buf.len() => Ok(()),
-            _ => Err(DecodeError::Eof)
+        match self.data {
+            Source::Slice(ref mut c) => match c.read(buf) {
+                Ok(n) if n == buf.len() => Ok(()),
+                _ => Err(DecodeError::new(DecodeErrorKind::Eof).at(c.position()))
+            },
+            Source::Reader(ref mut r) => {
+                let mut done = 0;
+
+                while done < buf.len() {
+                    let n = {
+                        let b = r.fill(min(buf.len() - done, READ_CHUNK_SIZE))?;
+                        if b.is_empty() {
+                            return Err(DecodeError::new(DecodeErrorKind::Eof)
+                                .at(r.pos));
+                        }
+                        let n = min(b.len(), buf.len() - done);
+                        buf[done..done + n].copy_from_slice(&b[..n]);
+                        n
+                    };
+                    r.pos += n as u64;
+                    done += n;
+                }
+
+                Ok(())
+            }
         }
     }
 
@@ -82,26 +625,26 @@ impl<'a> Decoder<'a> {
     }
 
     /// Reads a single byte from the stream without advancing the cursor.
-    pub fn peek_byte(&self) -> Result<u8, DecodeError> {
-        let n = self.data.position() as usize;
-        let data = self.data.get_ref();
-        if data.len() > n {
-            Ok(data[n])
-        } else {
-            Err(DecodeError::Eof)
-        }
+    ///
+    /// This takes `&mut self` because a `Decoder` reading from a reader
+    /// may need to read more data into its buffer.
+    pub fn peek_byte(&mut self) -> Result<u8, DecodeError> {
+        self.peek_bytes(1).map(|b| b[0])
     }
 
     /// Returns a slice of bytes without advancing the cursor.
     /// If fewer than `n` bytes are available, an error is returned.
-    pub fn peek_bytes(&self, n: usize) -> Result<&[u8], DecodeError> {
-        let pos = self.data.position() as usize;
-        let buf = self.data.get_ref();
+    pub fn peek_bytes(&mut self, n: usize) -> Result<&[u8], DecodeError> {
+        let pos = self.position();
+        let buf = match self.data {
+            Source::Slice(ref c) => c.get_ref().get(pos as usize..).unwrap_or(&[]),
+            Source::Reader(ref mut r) => r.fill(n)?,
+        };
 
-        if buf.len() < pos + n {
-            Err(DecodeError::Eof)
+        if buf.len() < n {
+            Err(DecodeError::new(DecodeErrorKind::Eof).at(pos + buf.len() as u64))
         } else {
-            Ok(&buf[pos..pos + n])
+            Ok(&buf[..n])
         }
     }
 
@@ -115,68 +658,251 @@ impl<'a> Decoder<'a> {
 
     /// Reads a number from the stream.
     /// This does not include the `i` prefix and `e` suffix.
+    ///
+    /// Numbers of more than 40 characters produce
+    /// `DecodeErrorKind::NumberLimitExceeded`.
     pub fn read_number<T: Integer>(&mut self) -> Result<T, DecodeError> {
-        let buf = self.read_while(is_number)?;
-        if buf.is_empty() ||
-                (buf.len() > 1 && buf[0] == b'0') ||
-                buf == b"-0" {
-            return Err(DecodeError::InvalidNumber);
-        }
+        let pos = self.position();
+        let buf = self.read_digits(MAX_NUMBER_DIGITS, DecodeErrorKind::NumberLimitExceeded)?;
         String::from_utf8(buf).ok().and_then(|s| s.parse().ok())
-            .ok_or(DecodeError::InvalidNumber)
+            .ok_or_else(|| DecodeError::new(DecodeErrorKind::InvalidNumber).at(pos))
+    }
+
+    /// Reads an integer of any magnitude from the stream,
+    /// returning its decimal representation.
+    ///
+    /// Integers of more than 40 characters count against the allocation limit.
+    pub fn read_big_integer(&mut self) -> Result<String, DecodeError> {
+        self.expect(b'i')?;
+        let limit = max(MAX_NUMBER_DIGITS,
+            self.options.max_alloc.saturating_sub(self.alloc));
+        let buf = self.read_digits(limit, DecodeErrorKind::AllocLimitExceeded)?;
+
+        if buf.len() > MAX_NUMBER_DIGITS {
+            self.alloc += buf.len();
+        }
+
+        self.expect(b'e')?;
+        Ok(String::from_utf8(buf).expect("integer is ASCII"))
+    }
+
+    /// Reads the characters of a number, returning them in canonical form.
+    ///
+    /// If more than `max` characters are found, an error of `kind` is returned.
+    fn read_digits(&mut self, max: usize, kind: DecodeErrorKind)
+            -> Result<Vec<u8>, DecodeError> {
+        let pos = self.position();
+        let mut buf = Vec::new();
+
+        while is_number(self.peek_byte()?) {
+            if buf.len() == max {
+                return Err(DecodeError::new(kind).at(pos));
+            }
+            buf.push(self.read_byte()?);
+        }
+
+        if !is_integer(&buf) {
+            return Err(DecodeError::new(DecodeErrorKind::InvalidNumber).at(pos));
+        }
+
+        if let Some(n) = normalize_number(&buf) {
+            if !self.options.lenient {
+                return Err(DecodeError::new(DecodeErrorKind::InvalidNumber).at(pos));
+            }
+            self.warn(DecodeWarningKind::NonCanonicalNumber, pos);
+            buf = n;
+        }
+
+        Ok(buf)
     }
 
     /// Reads a byte string from the stream.
     pub fn read_bytes(&mut self) -> Result<Vec<u8>, DecodeError> {
+        let n = self.read_len()?;
+        self.read_vec(n)
+    }
+
+    /// Reads the length prefix of a byte string, including the `:` suffix.
+    fn read_len(&mut self) -> Result<usize, DecodeError> {
+        let pos = self.position();
         let n: usize = self.read_number()?;
+        if n > self.options.max_bytes_len {
+            return Err(DecodeError::new(DecodeErrorKind::BytesLimitExceeded).at(pos));
+        }
         self.expect(b':')?;
-        if self.remaining() < n {
-            return Err(DecodeError::Eof);
+        Ok(n)
+    }
+
+    /// Reads `n` bytes from the stream into a `Vec`.
+    fn read_vec(&mut self, n: usize) -> Result<Vec<u8>, DecodeError> {
+        if n > self.options.max_alloc.saturating_sub(self.alloc) {
+            return Err(self.error(DecodeErrorKind::AllocLimitExceeded));
+        }
+        self.alloc += n;
+
+        match self.data {
+            Source::Slice(_) => {
+                if self.remaining() < n {
+                    return Err(self.error(DecodeErrorKind::Eof));
+                }
+                let mut buf = vec![0; n];
+                self.read(&mut buf)?;
+                Ok(buf)
+            }
+            Source::Reader(_) => {
+                // The length of the stream is unknown, so avoid allocating
+                // the full length until the data has actually been read.
+                let mut buf = Vec::new();
+
+                while buf.len() < n {
+                    let len = buf.len();
+                    buf.resize(len + min(n - len, READ_CHUNK_SIZE), 0);
+                    self.read(&mut buf[len..])?;
+                }
+
+                Ok(buf)
+            }
         }
-        let mut buf = vec![0; n];
-        self.read(&mut buf)?;
-        Ok(buf)
     }
 
     /// Reads a UTF-8 encoded string from the stream.
     pub fn read_str(&mut self) -> Result<String, DecodeError> {
+        let pos = self.position();
         String::from_utf8(self.read_bytes()?)
-            .map_err(|_| DecodeError::InvalidUtf8)
+            .map_err(|_| DecodeError::new(DecodeErrorKind::InvalidUtf8).at(pos))
     }
 
-    /// Reads a key value mapping from the stream.
-    pub fn read_dict<T: Decodable>(&mut self)
-            -> Result<BTreeMap<String, T>, DecodeError> {
-        self.expect(b'd')?;
-        let mut res = BTreeMap::new();
+    /// Reads a byte string from the stream, borrowing from the input.
+    ///
+    /// If the `Decoder` was constructed with `from_reader`,
+    /// `DecodeErrorKind::CannotBorrow` is returned.
+    pub fn read_bytes_ref(&mut self) -> Result<&'a [u8], DecodeError> {
+        if let Source::Reader(_) = self.data {
+            return Err(self.error(DecodeErrorKind::CannotBorrow));
+        }
+
+        let n = self.read_len()?;
+        if self.remaining() < n {
+            return Err(self.error(DecodeErrorKind::Eof));
+        }
 
-        while self.peek_byte()? != b'e' {
-            let k = self.read_str()?;
+        match self.data {
+            Source::Slice(ref mut c) => {
+                let data: &'a [u8] = c.get_ref();
+                let pos = c.position() as usize;
+                c.set_position((pos + n) as u64);
+                Ok(&data[pos..pos + n])
+            }
+            Source::Reader(_) => unreachable!(),
+        }
+    }
 
-            // Ensure that this key is greater than the greatest existing key
-            if !res.is_empty() {
-                let last: &String = res.keys().next_back().unwrap();
-                if k.as_bytes() <= last.as_bytes() {
-                    return Err(DecodeError::InvalidDict);
-                }
+    /// Reads the verbatim encoded representation of the current value.
+    pub fn read_raw(&mut self) -> Result<Vec<u8>, DecodeError> {
+        self.with_raw(|b| b.to_vec())
+    }
+
+    /// Reads the verbatim encoded representation of the current value,
+    /// borrowing from the input.
+    ///
+    /// If the `Decoder` was constructed with `from_reader`,
+    /// `DecodeErrorKind::CannotBorrow` is returned.
+    pub fn read_raw_ref(&mut self) -> Result<&'a [u8], DecodeError> {
+        let start = match self.data {
+            Source::Slice(ref c) => c.position() as usize,
+            Source::Reader(_) => return Err(self.error(DecodeErrorKind::CannotBorrow)),
+        };
+
+        self.skip_item()?;
+
+        match self.data {
+            Source::Slice(ref c) => {
+                let data: &'a [u8] = c.get_ref();
+                Ok(&data[start..c.position() as usize])
             }
+            Source::Reader(_) => unreachable!(),
+        }
+    }
 
-            let v = Decodable::decode(self)?;
-            res.insert(k, v);
+    /// Reads a UTF-8 encoded string from the stream, borrowing from the input.
+    ///
+    /// If the `Decoder` was constructed with `from_reader`,
+    /// `DecodeErrorKind::CannotBorrow` is returned.
+    pub fn read_str_ref(&mut self) -> Result<&'a str, DecodeError> {
+        let pos = self.position();
+        from_utf8(self.read_bytes_ref()?)
+            .map_err(|_| DecodeError::new(DecodeErrorKind::InvalidUtf8).at(pos))
+    }
+
+    /// Reads a byte string from the stream as a `&ByteStr`,
+    /// borrowing from the input.
+    ///
+    /// If the `Decoder` was constructed with `from_reader`,
+    /// `DecodeErrorKind::CannotBorrow` is returned.
+    pub fn read_byte_str(&mut self) -> Result<&'a ByteStr, DecodeError> {
+        self.read_bytes_ref().map(ByteStr::from_bytes)
+    }
+
+    /// Reads a byte string from the stream, borrowing from the input
+    /// if possible.
+    pub fn read_bytes_cow(&mut self) -> Result<Cow<'a, [u8]>, DecodeError> {
+        match self.data {
+            Source::Slice(_) => self.read_bytes_ref().map(Cow::Borrowed),
+            Source::Reader(_) => self.read_bytes().map(Cow::Owned),
         }
+    }
+
+    /// Reads a UTF-8 encoded string from the stream, borrowing from the input
+    /// if possible.
+    pub fn read_str_cow(&mut self) -> Result<Cow<'a, str>, DecodeError> {
+        match self.data {
+            Source::Slice(_) => self.read_str_ref().map(Cow::Borrowed),
+            Source::Reader(_) => self.read_str().map(Cow::Owned),
+        }
+    }
+
+    /// Reads a key value mapping from the stream.
+    ///
+    /// Keys are decoded as `K`, which may be any type decoded from a
+    /// byte string, such as `String` or `&str`.
+    pub fn read_dict<K, T>(&mut self) -> Result<BTreeMap<K, T>, DecodeError>
+            where K: DecodableRef<'a> + Ord + AsRef<[u8]>, T: DecodableRef<'a> {
+        self.expect(b'd')?;
+        let mut res = BTreeMap::new();
+        let mut keys = DictKeys::new();
+
+        self.nest(|d| {
+            while d.peek_byte()? != b'e' {
+                d.count_element()?;
+                let pos = d.position();
+                let k: K = DecodableRef::decode_ref(d)?;
+                let keep = keys.check(d, k.as_ref(), pos)?;
+
+                let v = d.read_nested(|| PathSegment::key(k.as_ref()))?;
+                if keep {
+                    res.insert(k, v);
+                }
+            }
+            Ok(())
+        })?;
 
         self.expect(b'e')?;
         Ok(res)
     }
 
     /// Reads a series of values from the stream.
-    pub fn read_list<T: Decodable>(&mut self) -> Result<Vec<T>, DecodeError> {
+    pub fn read_list<T: DecodableRef<'a>>(&mut self) -> Result<Vec<T>, DecodeError> {
         self.expect(b'l')?;
         let mut res = Vec::new();
 
-        while self.peek_byte()? != b'e' {
-            res.push(Decodable::decode(self)?);
-        }
+        self.nest(|d| {
+            while d.peek_byte()? != b'e' {
+                d.count_element()?;
+                let i = res.len();
+                res.push(d.read_nested(|| PathSegment::Index(i))?);
+            }
+            Ok(())
+        })?;
 
         self.expect(b'e')?;
         Ok(res)
@@ -188,93 +914,421 @@ impl<'a> Decoder<'a> {
     /// and `read_option` for any optional fields, in lexicographical order.
     pub fn read_struct<T, F>(&mut self, f: F) -> Result<T, DecodeError>
             where F: FnOnce(&mut Self) -> Result<T, DecodeError> {
-        self.expect(b'd')?;
-        let res = f(self)?;
+        self.read_struct_fields(None, f).map(|(t, _)| t)
+    }
 
-        // Skip any additional fields
-        while self.peek_byte()? != b'e' {
-            self.skip_item()?;
-            self.skip_item()?;
+    /// Reads a key value mapping from the stream as a `struct`,
+    /// returning any fields not read by the given callable.
+    ///
+    /// Fields are read as with `read_struct`. Those which are skipped,
+    /// either by `read_field` or after the callable returns, are decoded
+    /// as `Value` and returned. Passing the returned fields to
+    /// `Encoder::write_struct_with_rest` reproduces the original encoding.
+    pub fn read_struct_with_rest<T, F>(&mut self, f: F)
+            -> Result<(T, BTreeMap<ByteString, Value>), DecodeError>
+            where F: FnOnce(&mut Self) -> Result<T, DecodeError> {
+        self.read_struct_fields(Some(BTreeMap::new()), f)
+            .map(|(t, rest)| (t, rest.unwrap_or_default()))
+    }
+
+    fn read_struct_fields<T, F>(&mut self, rest: Option<BTreeMap<ByteString, Value>>, f: F)
+            -> Result<(T, Option<BTreeMap<ByteString, Value>>), DecodeError>
+            where F: FnOnce(&mut Self) -> Result<T, DecodeError> {
+        if self.options.lenient {
+            return self.read_struct_index(rest, f);
         }
 
+        self.expect(b'd')?;
+
+        let res = self.nest(|d| {
+            let start = d.position();
+            let (res, frame) = d.with_struct(StructFrame{index: None, start, rest}, f)?;
+            let mut rest = frame.rest;
+
+            // Skip or capture any additional fields
+            while d.peek_byte()? != b'e' {
+                d.count_element()?;
+
+                if let Some(ref mut rest) = rest {
+                    let key = d.read_bytes()?;
+                    let value = d.read_nested(|| PathSegment::key(&key))?;
+                    rest.insert(ByteString(key), value);
+                    continue;
+                }
+
+                if d.options.unknown_fields == UnknownFields::Skip {
+                    d.skip_item()?;
+                } else {
+                    let pos = d.position();
+                    let key = d.read_bytes()?;
+                    d.unknown_field(key, pos)?;
+                }
+
+                d.skip_item()?;
+            }
+
+            Ok((res, rest))
+        })?;
+
         self.expect(b'e')?;
         Ok(res)
     }
 
-    /// Reads a single field from the stream.
-    pub fn read_field<T: Decodable>(&mut self, name: &str) -> Result<T, DecodeError> {
-        let pos = self.data.position();
+    /// Reads a key value mapping from the stream as a `struct`,
+    /// allowing fields to be read in any order.
+    ///
+    /// The offset of each field is recorded before the given callable
+    /// is called. Within the callable, `read_field` and `read_option`
+    /// may be called in any order and any number of times for each field.
+    ///
+    /// For a `Decoder` constructed with `from_reader`, the entire dict
+    /// is retained in memory until the callable returns.
+    pub fn read_struct_indexed<T, F>(&mut self, f: F) -> Result<T, DecodeError>
+            where F: FnOnce(&mut Self) -> Result<T, DecodeError> {
+        self.read_struct_index(None, f).map(|(t, _)| t)
+    }
+
+    fn read_struct_index<T, F>(&mut self, rest: Option<BTreeMap<ByteString, Value>>, f: F)
+            -> Result<(T, Option<BTreeMap<ByteString, Value>>), DecodeError>
+            where F: FnOnce(&mut Self) -> Result<T, DecodeError> {
+        self.expect(b'd')?;
+
+        let res = self.nest(|d| d.with_pin(|d| {
+            let mut index = BTreeMap::new();
+            let mut keys = DictKeys::new();
+            let start = d.position();
+            let elements = d.elements;
+            let mut n = 0;
+
+            while d.peek_byte()? != b'e' {
+                d.count_element()?;
+                n += 1;
+                let key_pos = d.position();
+                let key = d.read_bytes()?;
+                let keep = keys.check(d, &key, key_pos)?;
+
+                let value_pos = d.position();
+                if keep {
+                    index.insert(key, IndexedField{key_pos, value_pos, read: false, counts: (0, 0)});
+                }
+                d.skip_item()?;
+            }
 
-        while self.peek_byte()? != b'e' {
-            let key = self.read_str()?;
+            // Field values will be counted as they are read
+            d.elements = elements + n;
 
-            if name == key {
-                return Decodable::decode(self);
-            } else if &key[..] < name {
-                // This key is less than name. name may be found later.
-                self.skip_item()?;
-            } else {
-                // This key is greater than name.
-                // We won't find name, so bail out now.
-                break;
+            let end = d.position();
+            let (res, frame) = d.with_struct(
+                StructFrame{index: Some(index), start, rest: None}, f)?;
+            let mut rest = rest;
+
+            for (key, field) in frame.index.into_iter().flatten() {
+                if field.read {
+                    continue;
+                }
+
+                if let Some(ref mut rest) = rest {
+                    d.set_position(field.value_pos);
+                    let warnings = d.warnings.len();
+                    let value = d.read_nested(|| PathSegment::key(&key))?;
+                    d.warnings.truncate(warnings);
+                    rest.insert(ByteString(key), value);
+                } else {
+                    d.unknown_field(key, field.key_pos)?;
+                }
             }
+
+            d.set_position(end);
+            Ok((res, rest))
+        }))?;
+
+        self.expect(b'e')?;
+        Ok(res)
+    }
+
+    /// Reads a single field from the stream.
+    ///
+    /// If the field is not found, an error of kind
+    /// `DecodeErrorKind::MissingField` is returned.
+    pub fn read_field<T: DecodableRef<'a>>(&mut self, name: &str) -> Result<T, DecodeError> {
+        if let Some(&mut StructFrame{index: Some(ref mut index), start, ..}) =
+                self.structs.last_mut() {
+            let counts = (self.elements, self.alloc);
+            let pos = index.get_mut(name.as_bytes()).map(|field| {
+                let read = field.read;
+                if !read {
+                    field.read = true;
+                    field.counts = counts;
+                }
+                (field.value_pos, read, field.counts)
+            });
+
+            return match pos {
+                Some((pos, read, (elements, alloc))) => {
+                    // A field read again has already been counted against limits,
+                    // so it is read as it was the first time.
+                    if read {
+                        self.elements = elements;
+                        self.alloc = alloc;
+                    }
+
+                    // Any warnings were reported when the struct was indexed
+                    let warnings = self.warnings.len();
+                    self.set_position(pos);
+                    let res = self.read_nested(|| PathSegment::Key(name.to_owned()));
+                    self.warnings.truncate(warnings);
+
+                    if read {
+                        self.elements = counts.0;
+                        self.alloc = counts.1;
+                    }
+                    res
+                }
+                None => Err(DecodeError::new(
+                    DecodeErrorKind::MissingField(name.to_owned())).at(start))
+            };
         }
 
-        self.data.set_position(pos);
-        Err(DecodeError::MissingField)
+        self.with_pin(|d| {
+            let pos = d.position();
+            let (elements, alloc, warnings) = (d.elements, d.alloc, d.warnings.len());
+            let capture = matches!(d.structs.last(), Some(&StructFrame{rest: Some(_), ..}));
+            let mut skipped = Vec::new();
+            let mut captured = Vec::new();
+
+            while d.peek_byte()? != b'e' {
+                let key_pos = d.position();
+                let key = d.read_bytes()?;
+
+                if name.as_bytes() == &key[..] {
+                    d.count_element()?;
+
+                    // Fields skipped on the way can no longer be read
+                    for (key, pos) in skipped {
+                        d.unknown_field(key, pos)?;
+                    }
+
+                    if let Some(&mut StructFrame{rest: Some(ref mut rest), ..}) =
+                            d.structs.last_mut() {
+                        rest.extend(captured);
+                    }
+
+                    return d.read_nested(|| PathSegment::Key(name.to_owned()));
+                } else if &key[..] < name.as_bytes() {
+                    // This key is less than name. name may be found later.
+                    d.count_element()?;
+
+                    if capture {
+                        let value = d.read_nested(|| PathSegment::key(&key))?;
+                        captured.push((ByteString(key), value));
+                    } else {
+                        d.skip_item()?;
+                        skipped.push((key, key_pos));
+                    }
+                } else {
+                    // This key is greater than name.
+                    // We won't find name, so bail out now.
+                    break;
+                }
+            }
+
+            // Keys will be read again, so don't count them against limits
+            d.set_position(pos);
+            d.elements = elements;
+            d.alloc = alloc;
+            d.warnings.truncate(warnings);
+            Err(DecodeError::new(DecodeErrorKind::MissingField(name.to_owned())).at(pos))
+        })
     }
 
     /// Reads an optional field from the stream.
-    pub fn read_option<T: Decodable>(&mut self, name: &str)
+    pub fn read_option<T: DecodableRef<'a>>(&mut self, name: &str)
             -> Result<Option<T>, DecodeError> {
         match self.read_field(name) {
             Ok(t) => Ok(Some(t)),
-            Err(DecodeError::MissingField) => Ok(None),
+            // A missing field within the value of this field is an error
+            Err(ref e) if e.path.is_empty() &&
+                    matches!(e.kind, DecodeErrorKind::MissingField(_)) => Ok(None),
             Err(e) => Err(e)
         }
     }
 
+    /// Reads an `enum` variant from the stream.
+    ///
+    /// A variant without data is encoded as a string containing its name;
+    /// a variant with data is encoded as a dict mapping its name to the data.
+    ///
+    /// The given callable is passed the variant name and whether data follows,
+    /// in which case it is expected to decode exactly one value.
+    /// If it returns `None`, an error of kind
+    /// `DecodeErrorKind::UnknownVariant` is returned.
+    pub fn read_variant<T, F>(&mut self, f: F) -> Result<T, DecodeError>
+            where F: FnOnce(&mut Self, &str, bool) -> Result<Option<T>, DecodeError> {
+        let pos = self.position();
+
+        if self.peek_byte()? != b'd' {
+            let name = self.read_str()?;
+
+            return match f(self, &name, false)? {
+                Some(t) => Ok(t),
+                None => Err(DecodeError::new(
+                    DecodeErrorKind::UnknownVariant(name)).at(pos))
+            };
+        }
+
+        self.read_byte()?;
+
+        let res = self.nest(|d| {
+            let pos = d.position();
+            let name = d.read_str()?;
+            d.count_element()?;
+            let value_pos = d.position();
+
+            match f(d, &name, true) {
+                Ok(Some(t)) => Ok(t),
+                Ok(None) => Err(DecodeError::new(
+                    DecodeErrorKind::UnknownVariant(name)).at(pos)),
+                Err(e) => Err(e.at(value_pos).within(PathSegment::Key(name)))
+            }
+        })?;
+
+        self.expect(b'e')?;
+        Ok(res)
+    }
+
     /// Advances the cursor beyond the current value.
+    ///
+    /// The value is validated as when decoding a `Value`.
     pub fn skip_item(&mut self) -> Result<(), DecodeError> {
         match self.peek_byte()? {
             b'd' => {
                 self.read_byte()?;
-                while self.peek_byte()? != b'e' {
-                    self.skip_item()?;
-                    self.skip_item()?;
-                }
-                self.expect(b'e')
-            }
-            b'i' => {
-                self.expect(b'i')?;
-                self.skip_while(is_number)?;
+                self.nest(|d| {
+                    let mut keys = DictKeys::new();
+
+                    while d.peek_byte()? != b'e' {
+                        d.count_element()?;
+                        let pos = d.position();
+                        let key = d.read_bytes_cow()?;
+                        keys.check(d, &key, pos)?;
+                        d.skip_item()?;
+                    }
+                    Ok(())
+                })?;
                 self.expect(b'e')
             }
+            b'i' => self.read_big_integer().map(|_| ()),
             b'l' => {
                 self.read_byte()?;
-                while self.peek_byte()? != b'e' {
-                    self.skip_item()?;
-                }
+                self.nest(|d| {
+                    while d.peek_byte()? != b'e' {
+                        d.count_element()?;
+                        d.skip_item()?;
+                    }
+                    Ok(())
+                })?;
                 self.expect(b'e')
             }
             b'0' ..= b'9' => {
-                let n = self.read_number()?;
-                self.expect(b':')?;
+                let n = self.read_len()?;
                 self.skip(n)?;
                 Ok(())
             }
-            b => Err(DecodeError::InvalidByte(b))
+            b => Err(self.error(DecodeErrorKind::InvalidByte(b)))
+        }
+    }
+
+    /// Reads a value from the stream, reporting its contents to `visitor`
+    /// as it is read.
+    ///
+    /// Dict keys are checked for order, and the limits of the decoder
+    /// options are enforced, as when decoding a value. An error returned by
+    /// the visitor ends the walk and is reported at the position of the
+    /// item being visited.
+    pub fn walk<V: Visitor<'a>>(&mut self, visitor: &mut V) -> Result<(), DecodeError> {
+        let pos = self.position();
+
+        match self.peek_byte()? {
+            b'd' => {
+                self.read_byte()?;
+                visitor.begin_dict().map_err(|e| e.at(pos))?;
+                self.nest(|d| {
+                    let mut keys = DictKeys::new();
+
+                    while d.peek_byte()? != b'e' {
+                        d.count_element()?;
+                        let pos = d.position();
+                        let key = d.read_bytes_cow()?;
+                        keys.check(d, &key, pos)?;
+
+                        visitor.key(key.clone()).map_err(|e| e.at(pos))?;
+                        d.walk(visitor).map_err(|e| e.within(PathSegment::key(&key)))?;
+                    }
+                    Ok(())
+                })?;
+                let end = self.position();
+                self.expect(b'e')?;
+                visitor.end_dict().map_err(|e| e.at(end))
+            }
+            b'l' => {
+                self.read_byte()?;
+                visitor.begin_list().map_err(|e| e.at(pos))?;
+                self.nest(|d| {
+                    let mut i = 0;
+
+                    while d.peek_byte()? != b'e' {
+                        d.count_element()?;
+                        d.walk(visitor).map_err(|e| e.within(PathSegment::Index(i)))?;
+                        i += 1;
+                    }
+                    Ok(())
+                })?;
+                let end = self.position();
+                self.expect(b'e')?;
+                visitor.end_list().map_err(|e| e.at(end))
+            }
+            b'i' => {
+                let s = self.read_big_integer()?;
+                match s.parse() {
+                    Ok(i) => visitor.visit_integer(i),
+                    Err(_) => visitor.visit_big_integer(&s)
+                }.map_err(|e| e.at(pos))
+            }
+            b'0' ..= b'9' => {
+                let b = self.read_bytes_cow()?;
+                visitor.visit_bytes(b).map_err(|e| e.at(pos))
+            }
+            b => Err(self.error(DecodeErrorKind::InvalidByte(b)))
         }
     }
 
     /// Advances the cursor `n` bytes.
     pub fn skip(&mut self, n: usize) -> Result<(), DecodeError> {
-        let pos = self.data.position();
-        if self.data.get_ref().len() < pos as usize + n {
-            Err(DecodeError::Eof)
-        } else {
-            self.data.set_position(pos + n as u64);
-            Ok(())
+        match self.data {
+            Source::Slice(ref mut c) => {
+                let pos = c.position();
+                if c.get_ref().len() < pos as usize + n {
+                    Err(DecodeError::new(DecodeErrorKind::Eof).at(pos))
+                } else {
+                    c.set_position(pos + n as u64);
+                    Ok(())
+                }
+            }
+            Source::Reader(ref mut r) => {
+                let mut done = 0;
+
+                while done < n {
+                    let avail = r.fill(min(n - done, READ_CHUNK_SIZE))?.len();
+                    if avail == 0 {
+                        return Err(DecodeError::new(DecodeErrorKind::Eof).at(r.pos));
+                    }
+                    let k = min(avail, n - done);
+                    r.pos += k as u64;
+                    done += k;
+                }
+
+                Ok(())
+            }
         }
     }
 
@@ -303,18 +1357,221 @@ impl<'a> Decoder<'a> {
 
     /// Returns an error if the next byte is not `byte`.
     pub fn expect(&mut self, byte: u8) -> Result<(), DecodeError> {
+        let pos = self.position();
         let b = self.read_byte()?;
         if b == byte {
             Ok(())
         } else {
-            Err(DecodeError::UnexpectedByte{expected: byte, found: b})
+            Err(DecodeError::new(DecodeErrorKind::UnexpectedByte{
+                expected: byte, found: b}).at(pos))
+        }
+    }
+
+    /// Returns an error of the given kind at the current position.
+    fn error(&self, kind: DecodeErrorKind) -> DecodeError {
+        DecodeError::new(kind).at(self.position())
+    }
+
+    /// Decodes a value nested within a list or dict,
+    /// adding its path to any error which occurs.
+    fn read_nested<T, F>(&mut self, segment: F) -> Result<T, DecodeError>
+            where T: DecodableRef<'a>, F: FnOnce() -> PathSegment {
+        let pos = self.position();
+        DecodableRef::decode_ref(self)
+            .map_err(|e| e.at(pos).within(segment()))
+    }
+
+    /// Calls `f` to decode the contents of a list or dict,
+    /// returning an error if the maximum depth is exceeded.
+    fn nest<T, F>(&mut self, f: F) -> Result<T, DecodeError>
+            where F: FnOnce(&mut Self) -> Result<T, DecodeError> {
+        self.enter()?;
+        let res = f(self);
+        self.leave();
+        res
+    }
+
+    /// Increments the nesting depth after reading the opening byte
+    /// of a list or dict, returning an error if the maximum is exceeded.
+    fn enter(&mut self) -> Result<(), DecodeError> {
+        if self.depth >= self.options.max_depth {
+            // Report the position of the opening `l` or `d`
+            return Err(DecodeError::new(DecodeErrorKind::DepthLimitExceeded)
+                .at(self.position() - 1));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    /// Decrements the nesting depth.
+    fn leave(&mut self) {
+        self.depth -= 1;
+    }
+
+    /// Records a list element or dict entry,
+    /// returning an error if the maximum number of elements is exceeded.
+    fn count_element(&mut self) -> Result<(), DecodeError> {
+        if self.elements >= self.options.max_elements {
+            return Err(self.error(DecodeErrorKind::ElementLimitExceeded));
+        }
+        self.elements += 1;
+        Ok(())
+    }
+
+    /// Calls `f` to read the fields of a `struct`,
+    /// returning its result along with the final state of the `struct`.
+    fn with_struct<T, F>(&mut self, frame: StructFrame, f: F)
+            -> Result<(T, StructFrame), DecodeError>
+            where F: FnOnce(&mut Self) -> Result<T, DecodeError> {
+        self.structs.push(frame);
+        let res = f(self);
+        let frame = self.structs.pop().expect("struct frame");
+        res.map(|t| (t, frame))
+    }
+
+    /// Records a violation accepted in lenient mode.
+    fn warn(&mut self, kind: DecodeWarningKind, pos: u64) {
+        self.warnings.push(DecodeWarning{kind, position: pos});
+    }
+
+    /// Handles a field at position `pos` which was not read
+    /// while decoding a `struct`.
+    fn unknown_field(&mut self, key: Vec<u8>, pos: u64) -> Result<(), DecodeError> {
+        match self.options.unknown_fields {
+            UnknownFields::Skip => Ok(()),
+            UnknownFields::Collect => {
+                self.skipped.push(SkippedField{key: ByteString(key), position: pos});
+                Ok(())
+            }
+            UnknownFields::Deny => Err(DecodeError::new(
+                DecodeErrorKind::UnknownField(String::from_utf8_lossy(&key).into_owned()))
+                .at(pos))
         }
     }
+
+    /// Advances the cursor beyond the current value,
+    /// passing its encoded representation to `f`.
+    fn with_raw<T, F>(&mut self, f: F) -> Result<T, DecodeError>
+            where F: FnOnce(&[u8]) -> T {
+        self.with_pin(|d| {
+            let start = d.position();
+            d.skip_item()?;
+            let end = d.position();
+            let len = (end - start) as usize;
+
+            d.set_position(start);
+            let res = f(d.peek_bytes(len)?);
+            d.set_position(end);
+            Ok(res)
+        })
+    }
+
+    /// Calls `f`, retaining any data read from a reader until `f` returns,
+    /// so that the cursor may be returned to the current position.
+    fn with_pin<T, F>(&mut self, f: F) -> Result<T, DecodeError>
+            where F: FnOnce(&mut Self) -> Result<T, DecodeError> {
+        if let Source::Reader(ref mut r) = self.data {
+            r.pins.push(r.pos);
+        }
+
+        let res = f(self);
+
+        if let Source::Reader(ref mut r) = self.data {
+            r.pins.pop();
+        }
+
+        res
+    }
 }
 
 /// Represents an error in a decoding operation.
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
-pub enum DecodeError {
+///
+/// In addition to the kind of error, a `DecodeError` records the position
+/// in the stream at which the error was found and the path to the value
+/// being decoded, e.g. `info.files[3].length`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DecodeError {
+    kind: DecodeErrorKind,
+    position: Option<u64>,
+    path: Vec<PathSegment>,
+}
+
+impl DecodeError {
+    /// Constructs a new `DecodeError` of the given kind.
+    ///
+    /// If the error is returned from a value decoded by `read_field`,
+    /// `read_list`, or `read_dict`, its position and path will be filled in.
+    pub fn new(kind: DecodeErrorKind) -> DecodeError {
+        DecodeError{kind, position: None, path: Vec::new()}
+    }
+
+    /// Returns the kind of error.
+    pub fn kind(&self) -> &DecodeErrorKind {
+        &self.kind
+    }
+
+    /// Returns the byte offset within the stream at which the error was found.
+    pub fn position(&self) -> Option<u64> {
+        self.position
+    }
+
+    /// Returns the path to the value in which the error was found.
+    ///
+    /// The path is empty if the error was found in the outermost value.
+    pub fn path(&self) -> &[PathSegment] {
+        &self.path
+    }
+
+    /// Sets the position of the error, if it has not already been set.
+    fn at(mut self, pos: u64) -> DecodeError {
+        if self.position.is_none() {
+            self.position = Some(pos);
+        }
+        self
+    }
+
+    /// Prepends a segment to the path of the error.
+    fn within(mut self, segment: PathSegment) -> DecodeError {
+        self.path.insert(0, segment);
+        self
+    }
+}
+
+impl From<DecodeErrorKind> for DecodeError {
+    fn from(kind: DecodeErrorKind) -> DecodeError {
+        DecodeError::new(kind)
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.kind, f)?;
+
+        if !self.path.is_empty() {
+            f.write_str(" at ")?;
+
+            for (i, seg) in self.path.iter().enumerate() {
+                match *seg {
+                    PathSegment::Key(ref k) if i == 0 => f.write_str(k)?,
+                    PathSegment::Key(ref k) => write!(f, ".{}", k)?,
+                    PathSegment::Index(n) => write!(f, "[{}]", n)?,
+                }
+            }
+        }
+
+        if let Some(pos) = self.position {
+            write!(f, " (byte {})", pos)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Error for DecodeError {}
+
+/// Describes the kind of error in a decoding operation.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DecodeErrorKind {
     /// End of bytes reached before expected
     Eof,
     /// Extraneous data at the end of the stream
@@ -327,8 +1584,28 @@ pub enum DecodeError {
     InvalidNumber,
     /// Invalid UTF-8 in a string
     InvalidUtf8,
-    /// Field not found while decoding `struct`
-    MissingField,
+    /// Error reading from the underlying reader
+    Io(io::ErrorKind),
+    /// Borrowed data requested from a `Decoder` reading from a reader
+    CannotBorrow,
+    /// Named field not found while decoding `struct`
+    MissingField(String),
+    /// Field not read while decoding `struct` in strict mode
+    UnknownField(String),
+    /// Variant name not recognized while decoding `enum`
+    UnknownVariant(String),
+    /// Lists and dicts nested beyond the maximum depth
+    DepthLimitExceeded,
+    /// Byte string longer than the maximum length
+    BytesLimitExceeded,
+    /// Lists and dicts contain more than the maximum number of elements
+    ElementLimitExceeded,
+    /// Byte strings or long integers require more than the maximum allocation
+    AllocLimitExceeded,
+    /// Number with more characters than may be read
+    NumberLimitExceeded,
+    /// Custom error, such as one produced by a `serde` implementation
+    Custom(String),
     /// Unexpected byte encountered
     UnexpectedByte{
         /// Byte expected
@@ -338,49 +1615,193 @@ pub enum DecodeError {
     },
 }
 
-impl fmt::Display for DecodeError {
+impl fmt::Display for DecodeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeErrorKind::Eof => f.write_str("unexpected end-of-file"),
+            DecodeErrorKind::ExtraneousData => f.write_str("extraneous data"),
+            DecodeErrorKind::InvalidByte(b) => write!(f, "invalid byte {:?}", b),
+            DecodeErrorKind::InvalidDict => f.write_str("invalid dict"),
+            DecodeErrorKind::InvalidNumber => f.write_str("invalid number"),
+            DecodeErrorKind::InvalidUtf8 => f.write_str("invalid utf-8"),
+            DecodeErrorKind::Io(kind) => write!(f, "i/o error: {:?}", kind),
+            DecodeErrorKind::CannotBorrow => f.write_str("cannot borrow from reader"),
+            DecodeErrorKind::MissingField(ref name) =>
+                write!(f, "missing field {:?}", name),
+            DecodeErrorKind::UnknownField(ref name) =>
+                write!(f, "unknown field {:?}", name),
+            DecodeErrorKind::UnknownVariant(ref name) =>
+                write!(f, "unknown variant {:?}", name),
+            DecodeErrorKind::DepthLimitExceeded =>
+                f.write_str("nesting depth limit exceeded"),
+            DecodeErrorKind::BytesLimitExceeded =>
+                f.write_str("byte string length limit exceeded"),
+            DecodeErrorKind::ElementLimitExceeded =>
+                f.write_str("element count limit exceeded"),
+            DecodeErrorKind::AllocLimitExceeded =>
+                f.write_str("allocation limit exceeded"),
+            DecodeErrorKind::NumberLimitExceeded =>
+                f.write_str("number length limit exceeded"),
+            DecodeErrorKind::Custom(ref msg) => f.write_str(msg),
+            DecodeErrorKind::UnexpectedByte{expected, found} =>
+                write!(f, "expected byte {:?}, found {:?}", expected, found),
+        }
+    }
+}
+
+/// Violation of the bencode format accepted by a `Decoder` in lenient mode.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DecodeWarning {
+    kind: DecodeWarningKind,
+    position: u64,
+}
+
+impl DecodeWarning {
+    /// Returns the kind of violation.
+    pub fn kind(&self) -> &DecodeWarningKind {
+        &self.kind
+    }
+
+    /// Returns the position in the stream at which the violation was found.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+}
+
+impl fmt::Display for DecodeWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (byte {})", self.kind, self.position)
+    }
+}
+
+/// Describes the kind of violation in a `DecodeWarning`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DecodeWarningKind {
+    /// Dict key less than the preceding key
+    UnsortedKey(String),
+    /// Dict key equal to a preceding key
+    DuplicateKey(String),
+    /// Number with leading zeros or negative zero
+    NonCanonicalNumber,
+    /// Data following a value; produced only by `validate_canonical`
+    ExtraneousData,
+}
+
+impl fmt::Display for DecodeWarningKind {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            DecodeError::Eof => f.write_str("unexpected end-of-file"),
-            DecodeError::ExtraneousData => f.write_str("extraneous data"),
-            DecodeError::InvalidByte(b) => write!(f, "invalid byte {:?}", b),
-            DecodeError::InvalidDict => f.write_str("invalid dict"),
-            DecodeError::InvalidNumber => f.write_str("invalid number"),
-            DecodeError::InvalidUtf8 => f.write_str("invalid utf-8"),
-            DecodeError::MissingField => f.write_str("missing field"),
-            DecodeError::UnexpectedByte{expected, found} =>
-                write!(f, "expected byte {:?}, found {:?}", expected, found),
+            DecodeWarningKind::UnsortedKey(ref key) =>
+                write!(f, "unsorted key {:?}", key),
+            DecodeWarningKind::DuplicateKey(ref key) =>
+                write!(f, "duplicate key {:?}", key),
+            DecodeWarningKind::NonCanonicalNumber =>
+                f.write_str("non-canonical number"),
+            DecodeWarningKind::ExtraneousData => f.write_str("extraneous data"),
         }
     }
 }
 
+/// Identifies a value nested within a list or dict.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PathSegment {
+    /// Dict key or `struct` field name
+    Key(String),
+    /// List index
+    Index(usize),
+}
+
+impl PathSegment {
+    /// Returns a `Key` segment for a byte string key.
+    fn key(key: &[u8]) -> PathSegment {
+        PathSegment::Key(String::from_utf8_lossy(key).into_owned())
+    }
+}
+
 /// Encodes values into a stream of bytes.
-#[derive(Clone)]
-pub struct Encoder {
-    data: Vec<u8>,
+pub struct Encoder<'a> {
+    data: Sink<'a>,
+    /// Structs currently being written, innermost last
+    structs: Vec<WriteFrame>,
+}
+
+/// State of a `struct` being written by `Encoder::write_struct`
+/// or `Encoder::write_struct_with_rest`.
+struct WriteFrame {
+    /// Encoded keys and values of additional fields not yet written,
+    /// in descending order
+    rest: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Name of the most recently written field
+    last: Option<String>,
 }
 
-impl Encoder {
+impl<'a> Default for Encoder<'a> {
+    fn default() -> Encoder<'a> {
+        Encoder::new()
+    }
+}
+
+/// Output produced by an `Encoder`.
+enum Sink<'a> {
+    /// Owned byte string
+    Bytes(Vec<u8>),
+    /// Writer
+    Writer(Box<dyn Write + 'a>),
+}
+
+impl<'a> Encoder<'a> {
     /// Constructs a new `Encoder`.
-    pub fn new() -> Encoder {
-        Encoder{data: Vec::new()}
+    pub fn new() -> Encoder<'a> {
+        Encoder::with_sink(Sink::Bytes(Vec::new()))
+    }
+
+    /// Constructs a new `Encoder`, writing to the given writer.
+    ///
+    /// Data is written to the writer as it is encoded. Callers writing to
+    /// a file or socket may wish to wrap the writer in an `io::BufWriter`.
+    pub fn from_writer<W: Write + 'a>(writer: W) -> Encoder<'a> {
+        Encoder::with_sink(Sink::Writer(Box::new(writer)))
+    }
+
+    fn with_sink(data: Sink<'a>) -> Encoder<'a> {
+        Encoder{
+            data,
+            structs: Vec::new(),
+        }
     }
 
     /// Consumes the `Encoder` and returns the encoded bytes.
-    pub fn into_bytes(self) -> Vec<u8> {
-        self.data
+    ///
+    /// Returns `None` if the `Encoder` was constructed with `from_writer`,
+    /// as its data has already been written to the writer.
+    pub fn into_bytes(self) -> Option<Vec<u8>> {
+        match self.data {
+            Sink::Bytes(b) => Some(b),
+            Sink::Writer(_) => None,
+        }
+    }
+
+    /// Flushes the underlying writer, if any.
+    pub fn flush(&mut self) -> Result<(), EncodeError> {
+        match self.data {
+            Sink::Bytes(_) => Ok(()),
+            Sink::Writer(ref mut w) => w.flush().map_err(EncodeError::from),
+        }
     }
 
     /// Writes a single byte to the stream.
     pub fn write_byte(&mut self, b: u8) -> Result<(), EncodeError> {
-        self.data.push(b);
-        Ok(())
+        self.write(&[b])
     }
 
     /// Writes a series of bytes to the stream.
     pub fn write(&mut self, b: &[u8]) -> Result<(), EncodeError> {
-        self.data.write(b).unwrap();
-        Ok(())
+        match self.data {
+            Sink::Bytes(ref mut v) => {
+                v.extend_from_slice(b);
+                Ok(())
+            }
+            Sink::Writer(ref mut w) => w.write_all(b).map_err(EncodeError::from),
+        }
     }
 
     /// Writes an integer value to the stream.
@@ -390,6 +1811,21 @@ impl Encoder {
         self.write_byte(b'e')
     }
 
+    /// Writes an integer of any magnitude, given as its decimal
+    /// representation, to the stream.
+    ///
+    /// If `s` is not an integer in canonical form, an error of kind
+    /// `EncodeError::InvalidNumber` is returned.
+    pub fn write_big_integer(&mut self, s: &str) -> Result<(), EncodeError> {
+        if !is_integer(s.as_bytes()) || normalize_number(s.as_bytes()).is_some() {
+            return Err(EncodeError::InvalidNumber(s.to_owned()));
+        }
+
+        self.write_byte(b'i')?;
+        self.write(s.as_bytes())?;
+        self.write_byte(b'e')
+    }
+
     /// Writes a number to the stream.
     /// This does not include `i` prefix and `e` suffix.
     pub fn write_number<T: Integer>(&mut self, t: T) -> Result<(), EncodeError> {
@@ -409,13 +1845,41 @@ impl Encoder {
     }
 
     /// Writes a key value mapping to the stream.
+    ///
+    /// Keys may be any type which can be viewed as a byte string,
+    /// such as `String` or `ByteString`.
     pub fn write_dict<K, V>(&mut self, map: &BTreeMap<K, V>)
             -> Result<(), EncodeError>
-            where K: Ord + AsRef<str>, V: Encodable {
+            where K: Ord + AsRef<[u8]>, V: Encodable {
         self.write_byte(b'd')?;
 
         for (k, v) in map.iter() {
-            self.write_str(k.as_ref())?;
+            self.write_bytes(k.as_ref())?;
+            v.encode(self)?;
+        }
+
+        self.write_byte(b'e')
+    }
+
+    /// Writes key value pairs, given in any order, to the stream as a dict.
+    ///
+    /// Pairs are sorted by the bytes of their keys before being written.
+    /// If two keys are equal, an error of kind `EncodeError::DuplicateField`
+    /// is returned.
+    pub fn write_unsorted_dict<K, V, I>(&mut self, iter: I) -> Result<(), EncodeError>
+            where I: IntoIterator<Item=(K, V)>, K: AsRef<[u8]>, V: Encodable {
+        let mut entries = iter.into_iter().collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.0.as_ref().cmp(b.0.as_ref()));
+
+        if let Some(w) = entries.windows(2).find(|w| w[0].0.as_ref() == w[1].0.as_ref()) {
+            return Err(EncodeError::DuplicateField(
+                String::from_utf8_lossy(w[0].0.as_ref()).into_owned()));
+        }
+
+        self.write_byte(b'd')?;
+
+        for (k, v) in entries {
+            self.write_bytes(k.as_ref())?;
             v.encode(self)?;
         }
 
@@ -436,16 +1900,87 @@ impl Encoder {
     ///
     /// The given callable is expected to call `write_field` for each field
     /// and `write_option` for any optional fields, in lexicographical order.
+    /// Fields written out of order produce an error.
     pub fn write_struct<F>(&mut self, f: F) -> Result<(), EncodeError>
             where F: FnOnce(&mut Self) -> Result<(), EncodeError> {
+        self.write_struct_fields(Vec::new(), f)
+    }
+
+    /// Writes a key value mapping from a `struct` to the stream,
+    /// including additional fields, such as those returned by
+    /// `Decoder::read_struct_with_rest`.
+    ///
+    /// Additional fields are written in order among the fields written
+    /// by the given callable. If a field is written by both, the additional
+    /// field is omitted.
+    pub fn write_struct_with_rest<K, V, F>(&mut self, rest: &BTreeMap<K, V>, f: F)
+            -> Result<(), EncodeError>
+            where K: Ord + AsRef<[u8]>, V: Encodable,
+                F: FnOnce(&mut Self) -> Result<(), EncodeError> {
+        let mut fields = Vec::with_capacity(rest.len());
+
+        for (k, v) in rest.iter().rev() {
+            fields.push((k.as_ref().to_vec(), encode(v)?));
+        }
+
+        self.write_struct_fields(fields, f)
+    }
+
+    fn write_struct_fields<F>(&mut self, rest: Vec<(Vec<u8>, Vec<u8>)>, f: F)
+            -> Result<(), EncodeError>
+            where F: FnOnce(&mut Self) -> Result<(), EncodeError> {
         self.write_byte(b'd')?;
-        f(self)?;
+
+        self.structs.push(WriteFrame{rest, last: None});
+        let res = f(self);
+        let frame = self.structs.pop().expect("struct frame");
+        res?;
+
+        for (k, v) in frame.rest.into_iter().rev() {
+            self.write_bytes(&k)?;
+            self.write(&v)?;
+        }
+
         self.write_byte(b'e')
     }
 
     /// Writes a single field to the stream.
+    ///
+    /// Within `write_struct`, if `name` is not greater than the name of
+    /// the preceding field, an error of kind `EncodeError::UnsortedField`
+    /// or `EncodeError::DuplicateField` is returned.
     pub fn write_field<T: ?Sized + Encodable>(&mut self, name: &str, t: &T)
             -> Result<(), EncodeError> {
+        let mut before = Vec::new();
+
+        if let Some(frame) = self.structs.last_mut() {
+            if let Some(ref last) = frame.last {
+                if name == last {
+                    return Err(EncodeError::DuplicateField(name.to_owned()));
+                } else if name < &last[..] {
+                    return Err(EncodeError::UnsortedField(name.to_owned()));
+                }
+            }
+            frame.last = Some(name.to_owned());
+
+            while let Some(last) = frame.rest.pop() {
+                if &last.0[..] < name.as_bytes() {
+                    before.push(last);
+                } else {
+                    // Omit an additional field of the same name
+                    if &last.0[..] > name.as_bytes() {
+                        frame.rest.push(last);
+                    }
+                    break;
+                }
+            }
+        }
+
+        for (k, v) in before {
+            self.write_bytes(&k)?;
+            self.write(&v)?;
+        }
+
         self.write_str(name)?;
         t.encode(self)
     }
@@ -462,29 +1997,146 @@ impl Encoder {
 
 /// Returns whether the given byte may appear in a number.
 fn is_number(b: u8) -> bool {
-    match b {
-        b'-' | b'0' ..= b'9' => true,
-        _ => false
+    matches!(b, b'-' | b'0' ..= b'9')
+}
+
+/// Returns whether a number consists of an optional `-` and one or more digits.
+fn is_integer(buf: &[u8]) -> bool {
+    let digits = buf.strip_prefix(b"-").unwrap_or(buf);
+    !digits.is_empty() && digits.iter().all(u8::is_ascii_digit)
+}
+
+/// Returns the canonical form of a number, if it has leading zeros
+/// or is negative zero.
+///
+/// `buf` must be a well-formed integer, as checked by `is_integer`.
+fn normalize_number(buf: &[u8]) -> Option<Vec<u8>> {
+    let (neg, digits) = match buf.split_first() {
+        Some((&b'-', rest)) => (true, rest),
+        _ => (false, buf)
+    };
+
+    let leading_zero = digits.len() > 1 && digits[0] == b'0';
+    let neg_zero = neg && digits.first() == Some(&b'0');
+
+    if !leading_zero && !neg_zero {
+        return None;
     }
+
+    let n = digits.iter().position(|&b| b != b'0').unwrap_or(digits.len() - 1);
+    let digits = &digits[n..];
+
+    let mut res = Vec::with_capacity(digits.len() + 1);
+    if neg && digits != b"0" {
+        res.push(b'-');
+    }
+    res.extend_from_slice(digits);
+    Some(res)
 }
 
 /// Represents an error in an encoding operation.
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum EncodeError {
-    // There are no encoding errors, but this exists in case we ever have any.
+    /// Error writing to the underlying writer
+    Io(io::ErrorKind),
+    /// Value of a type which cannot be represented in the bencode format
+    Unsupported(&'static str),
+    /// String passed to `write_big_integer` which is not a canonical integer
+    InvalidNumber(String),
+    /// Field written by `write_field` less than the preceding field
+    UnsortedField(String),
+    /// Field written by `write_field` more than once,
+    /// or duplicate key passed to `write_unsorted_dict`
+    DuplicateField(String),
+    /// Custom error produced by a `serde` implementation
+    Custom(String),
+}
+
+impl From<io::Error> for EncodeError {
+    fn from(e: io::Error) -> EncodeError {
+        EncodeError::Io(e.kind())
+    }
 }
 
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EncodeError::Io(kind) => write!(f, "i/o error: {:?}", kind),
+            EncodeError::Unsupported(ty) => write!(f, "unsupported type: {}", ty),
+            EncodeError::InvalidNumber(ref s) => write!(f, "invalid number {:?}", s),
+            EncodeError::UnsortedField(ref name) =>
+                write!(f, "field {:?} written out of order", name),
+            EncodeError::DuplicateField(ref name) =>
+                write!(f, "duplicate field {:?}", name),
+            EncodeError::Custom(ref msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl Error for EncodeError {}
+
 /// Represents a value decodable from a bencoded stream.
 pub trait Decodable: Sized {
     fn decode(d: &mut Decoder) -> Result<Self, DecodeError>;
 }
 
+/// Represents a value decodable from a bencoded stream,
+/// which may borrow data from the input with lifetime `'a`.
+///
+/// This is implemented for all `Decodable` types.
+pub trait DecodableRef<'a>: Sized {
+    fn decode_ref(d: &mut Decoder<'a>) -> Result<Self, DecodeError>;
+}
+
+impl<'a, T: Decodable> DecodableRef<'a> for T {
+    fn decode_ref(d: &mut Decoder<'a>) -> Result<T, DecodeError> {
+        Decodable::decode(d)
+    }
+}
+
+/// Receives the contents of a value read by `Decoder::walk`.
+///
+/// Each method does nothing by default. Byte strings may borrow from the
+/// input with lifetime `'a`.
+pub trait Visitor<'a> {
+    /// Called for an integer value.
+    fn visit_integer(&mut self, _i: i64) -> Result<(), DecodeError> { Ok(()) }
+
+    /// Called for an integer value outside the range of `i64`,
+    /// given as decimal digits.
+    fn visit_big_integer(&mut self, _s: &str) -> Result<(), DecodeError> { Ok(()) }
+
+    /// Called for a byte string value.
+    fn visit_bytes(&mut self, _b: Cow<'a, [u8]>) -> Result<(), DecodeError> { Ok(()) }
+
+    /// Called at the beginning of a list, before its elements.
+    fn begin_list(&mut self) -> Result<(), DecodeError> { Ok(()) }
+
+    /// Called at the end of a list, after its elements.
+    fn end_list(&mut self) -> Result<(), DecodeError> { Ok(()) }
+
+    /// Called at the beginning of a dict, before its entries.
+    fn begin_dict(&mut self) -> Result<(), DecodeError> { Ok(()) }
+
+    /// Called for the key of a dict entry, before its value.
+    fn key(&mut self, _key: Cow<'a, [u8]>) -> Result<(), DecodeError> { Ok(()) }
+
+    /// Called at the end of a dict, after its entries.
+    fn end_dict(&mut self) -> Result<(), DecodeError> { Ok(()) }
+}
+
 /// Represents a value encodable to a bencoded stream.
 pub trait Encodable {
     fn encode(&self, e: &mut Encoder) -> Result<(), EncodeError>;
 }
 
 /// An integer type that can be encoded and decoded.
+///
+/// # Safety
+///
+/// The `Display` and `FromStr` implementations must use only an optional
+/// leading `-` and ASCII decimal digits, as the `Display` representation
+/// is written verbatim into the encoded stream.
 pub unsafe trait Integer: Copy + fmt::Display + FromStr {}
 
 macro_rules! impl_integer {
@@ -493,12 +2145,13 @@ macro_rules! impl_integer {
     }
 }
 
-impl_integer!{ u8 u16 u32 u64 usize i8 i16 i32 i64 isize }
+impl_integer!{ u8 u16 u32 u64 u128 usize i8 i16 i32 i64 i128 isize }
 
 /// A borrowed byte string.
 ///
 /// This wrapper is necessary for a byte string to be encoded as a string
 /// in the bencode format rather than as a list.
+#[derive(Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct ByteStr {
     inner: [u8],
 }
@@ -520,6 +2173,12 @@ impl ByteStr {
     }
 }
 
+impl AsRef<[u8]> for ByteStr {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
 impl fmt::Debug for ByteStr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "ByteStr({:?})", self.as_bytes())
@@ -530,15 +2189,65 @@ impl fmt::Debug for ByteStr {
 ///
 /// This wrapper is necessary for a byte string to be decoded or encoded
 /// as a string in the bencode format rather than as a list.
-#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct ByteString(pub Vec<u8>);
 
+impl From<&str> for ByteString {
+    fn from(s: &str) -> ByteString {
+        ByteString(s.as_bytes().to_vec())
+    }
+}
+
+impl From<&[u8]> for ByteString {
+    fn from(b: &[u8]) -> ByteString {
+        ByteString(b.to_vec())
+    }
+}
+
+impl From<String> for ByteString {
+    fn from(s: String) -> ByteString {
+        ByteString(s.into_bytes())
+    }
+}
+
+impl From<Vec<u8>> for ByteString {
+    fn from(b: Vec<u8>) -> ByteString {
+        ByteString(b)
+    }
+}
+
+impl AsRef<[u8]> for ByteString {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Borrow<[u8]> for ByteString {
+    fn borrow(&self) -> &[u8] {
+        &self.0
+    }
+}
+
 impl AsRef<ByteStr> for ByteString {
     fn as_ref(&self) -> &ByteStr {
         ByteStr::from_bytes(&self.0)
     }
 }
 
+impl Borrow<ByteStr> for ByteString {
+    fn borrow(&self) -> &ByteStr {
+        ByteStr::from_bytes(&self.0)
+    }
+}
+
+impl ToOwned for ByteStr {
+    type Owned = ByteString;
+
+    fn to_owned(&self) -> ByteString {
+        ByteString(self.as_bytes().to_vec())
+    }
+}
+
 impl Deref for ByteString {
     type Target = ByteStr;
 
@@ -553,34 +2262,118 @@ pub struct Hash(pub [u8; 20]);
 impl Hash {
     /// Returns the SHA1 hash as a string of hexadecimal digits.
     pub fn to_hex(&self) -> String {
-        static HEX_CHARS: &'static [u8; 16] = b"0123456789abcdef";
-        let mut buf = [0; 40];
-
-        for (i, &b) in self.0.iter().enumerate() {
-            buf[i * 2    ] = HEX_CHARS[(b >> 4) as usize];
-            buf[i * 2 + 1] = HEX_CHARS[(b & 0xf) as usize];
-        }
-
-        unsafe { String::from_utf8_unchecked(buf.to_vec()) }
+        to_hex(&self.0)
     }
 }
 
 impl Decodable for Hash {
     fn decode(d: &mut Decoder) -> Result<Hash, DecodeError> {
         let mut hash = Hash([0; 20]);
-        let start = d.position();
-        d.skip_item()?;
-        let end = d.position();
-        let len = (end - start) as usize;
+        hash.0.copy_from_slice(&d.with_raw(|b| Sha1::digest(b))?);
+        Ok(hash)
+    }
+}
 
-        d.set_position(start);
-        let mut sha1 = Sha1::new();
-        sha1.update(d.peek_bytes(len)?);
-        sha1.output(&mut hash.0);
-        d.set_position(end);
+/// Contains the hash of the decoded value, computed by the digest
+/// algorithm `D` over its encoded representation.
+///
+/// `HashOf<Sha1>` gives the info hash of a BitTorrent v1 torrent,
+/// and `HashOf<Sha256>` that of a v2 torrent.
+pub struct HashOf<D: Digest>(pub Output<D>);
 
-        Ok(hash)
+impl<D: Digest> HashOf<D> {
+    /// Returns the hash as a byte slice.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Returns the hash as a string of hexadecimal digits.
+    pub fn to_hex(&self) -> String {
+        to_hex(&self.0)
+    }
+}
+
+impl<D: Digest> Clone for HashOf<D> {
+    fn clone(&self) -> HashOf<D> {
+        HashOf(self.0.clone())
+    }
+}
+
+impl<D: Digest> PartialEq for HashOf<D> {
+    fn eq(&self, rhs: &HashOf<D>) -> bool {
+        self.0 == rhs.0
+    }
+}
+
+impl<D: Digest> Eq for HashOf<D> {}
+
+impl<D: Digest> fmt::Debug for HashOf<D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("HashOf").field(&self.to_hex()).finish()
+    }
+}
+
+impl<D: Digest> Decodable for HashOf<D> {
+    fn decode(d: &mut Decoder) -> Result<HashOf<D>, DecodeError> {
+        d.with_raw(|b| D::digest(b)).map(HashOf)
+    }
+}
+
+/// Contains the verbatim encoded representation of a value.
+///
+/// The value is validated when decoded, and encoded unchanged, so that
+/// it may be preserved byte-for-byte, e.g. to retain its hash.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RawValue(pub Vec<u8>);
+
+impl Decodable for RawValue {
+    fn decode(d: &mut Decoder) -> Result<RawValue, DecodeError> {
+        d.read_raw().map(RawValue)
+    }
+}
+
+impl Encodable for RawValue {
+    fn encode(&self, e: &mut Encoder) -> Result<(), EncodeError> {
+        e.write(&self.0)
+    }
+}
+
+/// Contains the verbatim encoded representation of a value,
+/// borrowing from the input.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RawValueRef<'a>(pub &'a [u8]);
+
+impl<'a> RawValueRef<'a> {
+    /// Returns an owned `RawValue`, copying the borrowed data.
+    #[allow(clippy::should_implement_trait)]
+    pub fn to_owned(&self) -> RawValue {
+        RawValue(self.0.to_vec())
+    }
+}
+
+impl<'a> DecodableRef<'a> for RawValueRef<'a> {
+    fn decode_ref(d: &mut Decoder<'a>) -> Result<RawValueRef<'a>, DecodeError> {
+        d.read_raw_ref().map(RawValueRef)
+    }
+}
+
+impl<'a> Encodable for RawValueRef<'a> {
+    fn encode(&self, e: &mut Encoder) -> Result<(), EncodeError> {
+        e.write(self.0)
+    }
+}
+
+/// Formats bytes as a string of hexadecimal digits.
+fn to_hex(bytes: &[u8]) -> String {
+    static HEX_CHARS: &[u8; 16] = b"0123456789abcdef";
+    let mut buf = Vec::with_capacity(bytes.len() * 2);
+
+    for &b in bytes {
+        buf.push(HEX_CHARS[(b >> 4) as usize]);
+        buf.push(HEX_CHARS[(b & 0xf) as usize]);
     }
+
+    unsafe { String::from_utf8_unchecked(buf) }
 }
 
 /// Contains any valid bencode value.
@@ -588,6 +2381,8 @@ impl Decodable for Hash {
 pub enum Value {
     /// Integer value
     Integer(i64),
+    /// Integer value outside the range of `i64`, as decimal digits
+    BigInteger(String),
     /// Byte string value
     Bytes(Vec<u8>),
     /// UTF-8 string value
@@ -595,7 +2390,7 @@ pub enum Value {
     /// List value
     List(Vec<Value>),
     /// Dictionary value
-    Dict(BTreeMap<String, Value>),
+    Dict(BTreeMap<ByteString, Value>),
 }
 
 impl Value {
@@ -608,26 +2403,156 @@ impl Value {
             v => Err(v)
         }
     }
+
+    /// Returns the value for the given key of a `Dict` value.
+    ///
+    /// Returns `None` if the key is not present or the value is not a `Dict`.
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Option<&Value> {
+        match *self {
+            Value::Dict(ref d) => d.get(key.as_ref()),
+            _ => None
+        }
+    }
+}
+
+/// Contains any valid bencode value, borrowing data from the input.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ValueRef<'a> {
+    /// Integer value
+    Integer(i64),
+    /// Integer value outside the range of `i64`, as decimal digits
+    BigInteger(String),
+    /// Byte string value
+    Bytes(&'a [u8]),
+    /// UTF-8 string value
+    String(&'a str),
+    /// List value
+    List(Vec<ValueRef<'a>>),
+    /// Dictionary value
+    Dict(BTreeMap<&'a ByteStr, ValueRef<'a>>),
+}
+
+impl<'a> ValueRef<'a> {
+    /// Returns the byte string of a `Bytes` or `String` value.
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match *self {
+            ValueRef::Bytes(b) => Some(b),
+            ValueRef::String(s) => Some(s.as_bytes()),
+            _ => None
+        }
+    }
+
+    /// Returns the value for the given key of a `Dict` value.
+    ///
+    /// Returns `None` if the key is not present or the value is not a `Dict`.
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Option<&ValueRef<'a>> {
+        match *self {
+            ValueRef::Dict(ref d) => d.get(ByteStr::from_bytes(key.as_ref())),
+            _ => None
+        }
+    }
+
+    /// Returns an owned `Value`, copying all borrowed data.
+    #[allow(clippy::should_implement_trait)]
+    pub fn to_owned(&self) -> Value {
+        match *self {
+            ValueRef::Integer(i) => Value::Integer(i),
+            ValueRef::BigInteger(ref s) => Value::BigInteger(s.clone()),
+            ValueRef::Bytes(b) => Value::Bytes(b.to_vec()),
+            ValueRef::String(s) => Value::String(s.to_owned()),
+            ValueRef::List(ref l) => Value::List(
+                l.iter().map(|v| v.to_owned()).collect()),
+            ValueRef::Dict(ref d) => Value::Dict(
+                d.iter().map(|(k, v)| ((*k).to_owned(), v.to_owned())).collect()),
+        }
+    }
+}
+
+impl<'a> DecodableRef<'a> for ValueRef<'a> {
+    fn decode_ref(d: &mut Decoder<'a>) -> Result<ValueRef<'a>, DecodeError> {
+        match d.peek_byte()? {
+            b'd' => Ok(ValueRef::Dict(d.read_dict()?)),
+            b'i' => {
+                let s = d.read_big_integer()?;
+                match s.parse() {
+                    Ok(i) => Ok(ValueRef::Integer(i)),
+                    Err(_) => Ok(ValueRef::BigInteger(s))
+                }
+            }
+            b'l' => Ok(ValueRef::List(d.read_list()?)),
+            b'0' ..= b'9' => {
+                let b = d.read_bytes_ref()?;
+                match from_utf8(b) {
+                    Ok(s) => Ok(ValueRef::String(s)),
+                    Err(_) => Ok(ValueRef::Bytes(b))
+                }
+            }
+            b => Err(d.error(DecodeErrorKind::InvalidByte(b)))
+        }
+    }
 }
 
 impl Decodable for Value {
     fn decode(d: &mut Decoder) -> Result<Value, DecodeError> {
         match d.peek_byte()? {
             b'd' => Ok(Value::Dict(d.read_dict()?)),
-            b'i' => Ok(Value::Integer(d.read_integer()?)),
+            b'i' => {
+                let s = d.read_big_integer()?;
+                match s.parse() {
+                    Ok(i) => Ok(Value::Integer(i)),
+                    Err(_) => Ok(Value::BigInteger(s))
+                }
+            }
             b'l' => Ok(Value::List(d.read_list()?)),
             b'0' ..= b'9' => match String::from_utf8(d.read_bytes()?) {
                 Ok(s) => Ok(Value::String(s)),
                 Err(e) => Ok(Value::Bytes(e.into_bytes()))
             },
-            b => Err(DecodeError::InvalidByte(b))
+            b => Err(d.error(DecodeErrorKind::InvalidByte(b)))
         }
     }
 }
 
-impl Decodable for ByteString {
-    fn decode(d: &mut Decoder) -> Result<ByteString, DecodeError> {
-        d.read_bytes().map(ByteString)
+impl Decodable for ByteString {
+    fn decode(d: &mut Decoder) -> Result<ByteString, DecodeError> {
+        d.read_bytes().map(ByteString)
+    }
+}
+
+/// Decodes a byte string, borrowing from the input.
+///
+/// Note that, unlike `Vec<u8>`, this is decoded from a byte string rather than
+/// a list. Use `ByteStr` to encode a byte slice as a byte string.
+impl<'a> DecodableRef<'a> for &'a [u8] {
+    fn decode_ref(d: &mut Decoder<'a>) -> Result<&'a [u8], DecodeError> {
+        d.read_bytes_ref()
+    }
+}
+
+impl<'a> DecodableRef<'a> for &'a str {
+    fn decode_ref(d: &mut Decoder<'a>) -> Result<&'a str, DecodeError> {
+        d.read_str_ref()
+    }
+}
+
+impl<'a> DecodableRef<'a> for &'a ByteStr {
+    fn decode_ref(d: &mut Decoder<'a>) -> Result<&'a ByteStr, DecodeError> {
+        d.read_byte_str()
+    }
+}
+
+impl<'a> DecodableRef<'a> for Cow<'a, str> {
+    fn decode_ref(d: &mut Decoder<'a>) -> Result<Cow<'a, str>, DecodeError> {
+        d.read_str_cow()
+    }
+}
+
+impl<'a> DecodableRef<'a> for Cow<'a, ByteStr> {
+    fn decode_ref(d: &mut Decoder<'a>) -> Result<Cow<'a, ByteStr>, DecodeError> {
+        Ok(match d.read_bytes_cow()? {
+            Cow::Borrowed(b) => Cow::Borrowed(ByteStr::from_bytes(b)),
+            Cow::Owned(b) => Cow::Owned(ByteString(b)),
+        })
     }
 }
 
@@ -643,7 +2568,7 @@ macro_rules! impl_decodable_integer {
     }
 }
 
-impl_decodable_integer!{ u8 u16 u32 u64 usize i8 i16 i32 i64 isize }
+impl_decodable_integer!{ u8 u16 u32 u64 u128 usize i8 i16 i32 i64 i128 isize }
 
 impl<T: Decodable> Decodable for Box<T> {
     fn decode(d: &mut Decoder) -> Result<Box<T>, DecodeError> {
@@ -675,16 +2600,26 @@ impl Decodable for String {
     }
 }
 
-impl<T: Decodable> Decodable for BTreeMap<String, T> {
-    fn decode(d: &mut Decoder) -> Result<BTreeMap<String, T>, DecodeError> {
+impl<K, T> Decodable for BTreeMap<K, T>
+        where K: Decodable + Ord + AsRef<[u8]>, T: Decodable {
+    fn decode(d: &mut Decoder) -> Result<BTreeMap<K, T>, DecodeError> {
         d.read_dict()
     }
 }
 
+impl<K, T, S> Decodable for HashMap<K, T, S>
+        where K: Decodable + Ord + hash::Hash + AsRef<[u8]>, T: Decodable,
+            S: BuildHasher + Default {
+    fn decode(d: &mut Decoder) -> Result<HashMap<K, T, S>, DecodeError> {
+        d.read_dict::<K, T>().map(|m| m.into_iter().collect())
+    }
+}
+
 impl Encodable for Value {
     fn encode(&self, e: &mut Encoder) -> Result<(), EncodeError> {
         match *self {
             Value::Integer(i) => e.write_integer(i),
+            Value::BigInteger(ref s) => e.write_big_integer(s),
             Value::Bytes(ref b) => e.write_bytes(b),
             Value::String(ref s) => e.write_str(s),
             Value::List(ref l) => e.write_list(l),
@@ -693,6 +2628,19 @@ impl Encodable for Value {
     }
 }
 
+impl<'a> Encodable for ValueRef<'a> {
+    fn encode(&self, e: &mut Encoder) -> Result<(), EncodeError> {
+        match *self {
+            ValueRef::Integer(i) => e.write_integer(i),
+            ValueRef::BigInteger(ref s) => e.write_big_integer(s),
+            ValueRef::Bytes(b) => e.write_bytes(b),
+            ValueRef::String(s) => e.write_str(s),
+            ValueRef::List(ref l) => e.write_list(l),
+            ValueRef::Dict(ref d) => e.write_dict(d),
+        }
+    }
+}
+
 impl Encodable for ByteStr {
     fn encode(&self, e: &mut Encoder) -> Result<(), EncodeError> {
         e.write_bytes(self.as_bytes())
@@ -717,7 +2665,7 @@ macro_rules! impl_encodable_integer {
     }
 }
 
-impl_encodable_integer!{ u8 u16 u32 u64 usize i8 i16 i32 i64 isize }
+impl_encodable_integer!{ u8 u16 u32 u64 u128 usize i8 i16 i32 i64 i128 isize }
 
 impl<T: Encodable> Encodable for Box<T> {
     fn encode(&self, e: &mut Encoder) -> Result<(), EncodeError> {
@@ -749,12 +2697,24 @@ impl<T: Encodable> Encodable for Vec<T> {
     }
 }
 
-impl<'a, T: ?Sized + Encodable> Encodable for &'a T {
+impl<T: ?Sized + Encodable> Encodable for &T {
     fn encode(&self, e: &mut Encoder) -> Result<(), EncodeError> {
         (**self).encode(e)
     }
 }
 
+impl<'a> Encodable for Cow<'a, str> {
+    fn encode(&self, e: &mut Encoder) -> Result<(), EncodeError> {
+        e.write_str(self)
+    }
+}
+
+impl<'a> Encodable for Cow<'a, ByteStr> {
+    fn encode(&self, e: &mut Encoder) -> Result<(), EncodeError> {
+        e.write_bytes(self.as_bytes())
+    }
+}
+
 impl Encodable for str {
     fn encode(&self, e: &mut Encoder) -> Result<(), EncodeError> {
         e.write_str(self)
@@ -767,18 +2727,36 @@ impl Encodable for String {
     }
 }
 
-impl<K: Ord + AsRef<str>, V: Encodable> Encodable for BTreeMap<K, V> {
+impl<K: Ord + AsRef<[u8]>, V: Encodable> Encodable for BTreeMap<K, V> {
     fn encode(&self, e: &mut Encoder) -> Result<(), EncodeError> {
         e.write_dict(self)
     }
 }
 
+impl<K, V, S> Encodable for HashMap<K, V, S>
+        where K: Eq + hash::Hash + AsRef<[u8]>, V: Encodable, S: BuildHasher {
+    fn encode(&self, e: &mut Encoder) -> Result<(), EncodeError> {
+        e.write_unsorted_dict(self)
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use std::borrow::Cow;
     use std::collections::BTreeMap;
-    use super::{decode, encode, Decoder, Encoder};
-    use super::{ByteStr, ByteString, Hash, Value};
-    use super::{Decodable, Encodable, DecodeError, EncodeError};
+    use std::io::{self, Read};
+    use super::{decode, decode_from, decode_ref, encode, encode_to};
+    use super::{Decoder, DecoderOptions, Encoder, UnknownFields};
+    use super::{ByteStr, ByteString, Hash, HashOf, RawValue, RawValueRef, Sha1, Sha256};
+    use super::{Value, ValueRef};
+    use digest::Digest;
+    use super::{Decodable, DecodableRef, Encodable, EncodeError};
+    use super::{DecodeError, DecodeErrorKind, PathSegment};
+
+    /// Returns the kind of a decoding error.
+    fn kind<T>(r: Result<T, DecodeError>) -> Result<T, DecodeErrorKind> {
+        r.map_err(|e| e.kind().clone())
+    }
 
     #[test]
     fn test_decoder() {
@@ -791,12 +2769,19 @@ mod test {
 
         assert_eq!(d.read_str(), Ok("spam".to_string()));
         assert_eq!(d.read_bytes(), Ok(b"eggs".to_vec()));
+
+        let mut c = d.try_clone().unwrap();
+        assert_eq!(c.read_integer(), Ok(123));
+        assert_eq!(c.position(), d.position() + 5);
+
         assert_eq!(d.read_integer(), Ok(123));
         assert_eq!(d.read_list(), Ok(vec![1,2,3]));
         let mut m = BTreeMap::new();
         m.insert("foo".to_string(), "bar".to_string());
         assert_eq!(d.read_dict(), Ok(m));
         assert_eq!(d.finish(), Ok(()));
+
+        assert!(Decoder::from_reader(&b"i1e"[..]).try_clone().is_none());
     }
 
     #[test]
@@ -811,7 +2796,7 @@ mod test {
         m.insert("foo", "bar");
         e.write_dict(&m).unwrap();
 
-        assert_eq!(e.into_bytes(), &b"\
+        assert_eq!(e.into_bytes().unwrap(), &b"\
             4:spam\
             4:eggs\
             i123e\
@@ -819,6 +2804,27 @@ mod test {
             d3:foo3:bare"[..]);
     }
 
+    #[test]
+    fn test_writer() {
+        let mut buf = Vec::new();
+
+        {
+            let mut e = Encoder::from_writer(&mut buf);
+            e.write_str("spam").unwrap();
+            e.write_list(&[1, 2, 3][..]).unwrap();
+            e.flush().unwrap();
+        }
+
+        encode_to(&mut buf, "eggs").unwrap();
+
+        assert_eq!(buf, &b"4:spamli1ei2ei3ee4:eggs"[..]);
+        assert_eq!(Encoder::from_writer(Vec::new()).into_bytes(), None);
+
+        let mut short = [0; 4];
+        assert_eq!(encode_to(&mut short[..], "spam"),
+            Err(EncodeError::Io(io::ErrorKind::WriteZero)));
+    }
+
     #[test]
     fn test_encode() {
         let mut e = Encoder::new();
@@ -827,7 +2833,7 @@ mod test {
         ByteStr::from_bytes(b"bar").encode(&mut e).unwrap();
         [1, 2, 3].encode(&mut e).unwrap();
 
-        assert_eq!(e.into_bytes(), &b"\
+        assert_eq!(e.into_bytes().unwrap(), &b"\
             3:foo\
             3:bar\
             li1ei2ei3ee\
@@ -836,15 +2842,125 @@ mod test {
 
     #[test]
     fn test_errors() {
-        assert_eq!(decode::<String>(b"10:foo"), Err(DecodeError::Eof));
-        assert_eq!(decode::<BTreeMap<String, String>>(b"d3:foo"),
-            Err(DecodeError::Eof));
-        assert_eq!(decode::<i32>(b"i-0e"), Err(DecodeError::InvalidNumber));
-        assert_eq!(decode::<i32>(b"i01e"), Err(DecodeError::InvalidNumber));
-        assert_eq!(decode::<BTreeMap<String, i32>>(
-            b"d3:fooi0e3:fooi0ee"), Err(DecodeError::InvalidDict));
-        assert_eq!(decode::<BTreeMap<String, i32>>(
-            b"d3:fooi0e3:bari0ee"), Err(DecodeError::InvalidDict));
+        assert_eq!(kind(decode::<String>(b"10:foo")), Err(DecodeErrorKind::Eof));
+        assert_eq!(kind(decode::<BTreeMap<String, String>>(b"d3:foo")),
+            Err(DecodeErrorKind::Eof));
+        assert_eq!(kind(decode::<i32>(b"i-0e")), Err(DecodeErrorKind::InvalidNumber));
+        assert_eq!(kind(decode::<i32>(b"i01e")), Err(DecodeErrorKind::InvalidNumber));
+        assert_eq!(kind(decode::<BTreeMap<String, i32>>(
+            b"d3:fooi0e3:fooi0ee")), Err(DecodeErrorKind::InvalidDict));
+        assert_eq!(kind(decode::<BTreeMap<String, i32>>(
+            b"d3:fooi0e3:bari0ee")), Err(DecodeErrorKind::InvalidDict));
+    }
+
+    #[test]
+    fn test_error_context() {
+        let e = decode::<BTreeMap<String, Vec<Test2>>>(
+            b"d4:listld3:fooi1eed3:fooi01eeee").unwrap_err();
+
+        assert_eq!(*e.kind(), DecodeErrorKind::InvalidNumber);
+        assert_eq!(e.position(), Some(25));
+        assert_eq!(e.path(), &[
+            PathSegment::Key("list".to_string()),
+            PathSegment::Index(1),
+            PathSegment::Key("foo".to_string()),
+        ]);
+        assert_eq!(e.to_string(), "invalid number at list[1].foo (byte 25)");
+
+        let e = decode::<Vec<Test2>>(b"ld3:bari1eee").unwrap_err();
+
+        assert_eq!(*e.kind(), DecodeErrorKind::MissingField("foo".to_string()));
+        assert_eq!(e.position(), Some(10));
+        assert_eq!(e.path(), &[PathSegment::Index(0)]);
+
+        // A missing field within an optional field is not ignored
+        assert_eq!(decode::<Option2>(b"de"), Ok(Option2{foo: None}));
+        let e = decode::<Option2>(b"d3:food3:bari1eee").unwrap_err();
+
+        assert_eq!(*e.kind(), DecodeErrorKind::MissingField("foo".to_string()));
+        assert_eq!(e.path(), &[PathSegment::Key("foo".to_string())]);
+    }
+
+    #[test]
+    fn test_depth_limit() {
+        let mut bytes = vec![b'l'; 100_000];
+        bytes.extend(vec![b'e'; 100_000]);
+
+        assert_eq!(kind(decode::<Value>(&bytes)),
+            Err(DecodeErrorKind::DepthLimitExceeded));
+        assert_eq!(kind(Decoder::new(&bytes).skip_item()),
+            Err(DecodeErrorKind::DepthLimitExceeded));
+
+        let mut d = Decoder::new(b"lld1:ai1eeee");
+        d.set_max_depth(3);
+        assert!(Value::decode(&mut d).is_ok());
+
+        let mut d = Decoder::new(b"lld1:ai1eeee");
+        d.set_max_depth(2);
+        let e = Value::decode(&mut d).unwrap_err();
+        assert_eq!(*e.kind(), DecodeErrorKind::DepthLimitExceeded);
+        assert_eq!(e.position(), Some(2));
+    }
+
+    #[test]
+    fn test_limits() {
+        fn decode_with<T: Decodable>(data: &[u8], opts: DecoderOptions)
+                -> Result<T, DecodeErrorKind> {
+            let mut d = Decoder::new(data);
+            d.set_options(opts);
+            kind(T::decode(&mut d))
+        }
+
+        let opts = DecoderOptions::new().max_bytes_len(3);
+        assert_eq!(decode_with::<Vec<String>>(b"l3:foo3:bare", opts),
+            Ok(vec!["foo".to_string(), "bar".to_string()]));
+        assert_eq!(decode_with::<Vec<String>>(b"l3:foo4:spame", opts),
+            Err(DecodeErrorKind::BytesLimitExceeded));
+        let mut d = Decoder::new(b"4:spam");
+        d.set_options(opts);
+        assert_eq!(kind(d.skip_item()), Err(DecodeErrorKind::BytesLimitExceeded));
+
+        let opts = DecoderOptions::new().max_elements(2);
+        assert_eq!(decode_with::<Vec<Vec<i32>>>(b"lli1eee", opts),
+            Ok(vec![vec![1]]));
+        assert_eq!(decode_with::<Vec<Vec<i32>>>(b"lli1ei2eee", opts),
+            Err(DecodeErrorKind::ElementLimitExceeded));
+        assert_eq!(decode_with::<Test2>(b"d1:ai1e3:fooi1ee", opts),
+            Ok(Test2{bar: None, foo: 1}));
+        assert_eq!(decode_with::<Test2>(b"d1:ai1e3:fooi1e1:zi1e1:zi1ee", opts),
+            Err(DecodeErrorKind::ElementLimitExceeded));
+
+        let opts = DecoderOptions::new().max_alloc(6);
+        assert_eq!(decode_with::<Vec<String>>(b"l3:foo3:bare", opts),
+            Ok(vec!["foo".to_string(), "bar".to_string()]));
+        assert_eq!(decode_with::<Vec<String>>(b"l3:foo3:bar1:ze", opts),
+            Err(DecodeErrorKind::AllocLimitExceeded));
+
+        // Lowering the limit below the amount already allocated
+        let mut d = Decoder::new(b"3:foo3:bar");
+        d.set_options(opts);
+        d.read_bytes().unwrap();
+        d.set_options(DecoderOptions::new().max_alloc(2));
+        assert_eq!(kind(d.read_bytes()), Err(DecodeErrorKind::AllocLimitExceeded));
+
+        // Digits of numbers are limited
+        let mut long = b"i".to_vec();
+        long.resize(1_000_001, b'1');
+        long.push(b'e');
+        let opts = DecoderOptions::new().max_alloc(16).max_bytes_len(16);
+        let mut d = Decoder::from_reader(&long[..]);
+        d.set_options(opts);
+        assert_eq!(kind(Value::decode(&mut d)), Err(DecodeErrorKind::AllocLimitExceeded));
+        assert_eq!(decode_with::<i64>(&long, opts),
+            Err(DecodeErrorKind::NumberLimitExceeded));
+        assert_eq!(decode_with::<ByteString>(&long[1..], opts),
+            Err(DecodeErrorKind::NumberLimitExceeded));
+
+        let big = format!("i{}e", "9".repeat(50));
+        assert_eq!(decode_with::<Value>(big.as_bytes(), DecoderOptions::new()),
+            Ok(Value::BigInteger("9".repeat(50))));
+        assert_eq!(decode_with::<Value>(big.as_bytes(), opts),
+            Err(DecodeErrorKind::AllocLimitExceeded));
     }
 
     #[test]
@@ -854,6 +2970,76 @@ mod test {
         let hash = Hash::decode(&mut d).unwrap();
         assert_eq!(d.finish(), Ok(()));
         assert_eq!(hash.to_hex(), "6d2262126feb6ec7bd3464935025c8c609c0119d");
+
+        let mut d = Decoder::new(&b"d3:foo3:bare"[..]);
+        let hash = HashOf::<Sha1>::decode(&mut d).unwrap();
+        assert_eq!(hash.to_hex(), "6d2262126feb6ec7bd3464935025c8c609c0119d");
+
+        let hash = decode::<HashOf<Sha256>>(b"d3:foo3:bare").unwrap();
+        assert_eq!(hash.as_bytes().len(), 32);
+        assert_eq!(hash, HashOf(Sha256::digest(b"d3:foo3:bare")));
+
+        let hash = decode_from::<HashOf<Sha256>, _>(Trickle(b"li1ee")).unwrap();
+        assert_eq!(hash, HashOf(Sha256::digest(b"li1ee")));
+
+        // Values that do not decode as a `Value` are not hashed
+        for data in &[&b"ie"[..], b"i0-1e", b"d1:bi1e1:ai1ee"] {
+            let err = decode::<Value>(data).err();
+            assert!(err.is_some());
+            assert_eq!(decode::<Hash>(data).err(), err);
+            assert_eq!(decode::<HashOf<Sha1>>(data).err(), err);
+            assert_eq!(decode_from::<Hash, _>(Trickle(data)).err(), err);
+        }
+    }
+
+    /// Yields one byte per call to `read`.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() || buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.0[0];
+            self.0 = &self.0[1..];
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn test_reader() {
+        let mut d = Decoder::from_reader(Trickle(b"\
+            4:spam\
+            i123e\
+            li1ei2ei3ee\
+            d3:foo3:bare\
+            d3:foo3:bare"));
+
+        assert_eq!(d.read_str(), Ok("spam".to_string()));
+        assert_eq!(d.read_integer(), Ok(123));
+        assert_eq!(d.read_list(), Ok(vec![1, 2, 3]));
+        let mut m = BTreeMap::new();
+        m.insert("foo".to_string(), "bar".to_string());
+        assert_eq!(d.read_dict(), Ok(m));
+
+        let hash = Hash::decode(&mut d).unwrap();
+        assert_eq!(hash.to_hex(), "6d2262126feb6ec7bd3464935025c8c609c0119d");
+        assert_eq!(d.finish(), Ok(()));
+
+        assert_eq!(kind(decode_from::<String, _>(&b"10:foo"[..])),
+            Err(DecodeErrorKind::Eof));
+        assert_eq!(kind(decode_from::<String, _>(&b"3:fooi1e"[..])),
+            Err(DecodeErrorKind::ExtraneousData));
+    }
+
+    #[test]
+    fn test_reader_large() {
+        let data = vec![0xab; 100_000];
+        let mut bytes = b"100000:".to_vec();
+        bytes.extend_from_slice(&data);
+
+        assert_eq!(decode_from::<ByteString, _>(&bytes[..]),
+            Ok(ByteString(data)));
     }
 
     #[derive(Debug, Eq, PartialEq)]
@@ -944,6 +3130,21 @@ mod test {
         }
     }
 
+    #[derive(Debug, Eq, PartialEq)]
+    struct Option2 {
+        foo: Option<Test2>,
+    }
+
+    impl Decodable for Option2 {
+        fn decode(d: &mut Decoder) -> Result<Option2, DecodeError> {
+            d.read_struct(|d| {
+                Ok(Option2{
+                    foo: d.read_option("foo")?,
+                })
+            })
+        }
+    }
+
     #[test]
     fn test_struct_fields() {
         let mut d = Decoder::new(&b"\
@@ -968,6 +3169,496 @@ mod test {
 
         assert_eq!(Test2::decode(&mut d),
             Ok(Test2{bar: None, foo: 111}));
+
+        let mut d = Decoder::from_reader(Trickle(b"\
+            d\
+            1:a6:lalala\
+            6:dadadai1e\
+            3:fooi111e\
+            2:zzi1e\
+            e"));
+
+        assert_eq!(Test2::decode(&mut d),
+            Ok(Test2{bar: None, foo: 111}));
+        assert_eq!(d.finish(), Ok(()));
+    }
+
+    #[test]
+    fn test_struct_indexed() {
+        fn read_test2(d: &mut Decoder) -> Result<Test2, DecodeError> {
+            d.read_struct_indexed(|d| {
+                let foo = d.read_field("foo")?;
+                let bar = d.read_option("bar")?;
+                let foo2: i32 = d.read_field("foo")?;
+                assert_eq!(foo, foo2);
+                Ok(Test2{bar, foo})
+            })
+        }
+
+        let bytes = b"d1:ali1ee3:bari999e3:fooi111e2:zzi1ee";
+
+        let mut d = Decoder::new(bytes);
+        assert_eq!(read_test2(&mut d), Ok(Test2{bar: Some(999), foo: 111}));
+        assert_eq!(d.finish(), Ok(()));
+
+        let mut d = Decoder::from_reader(Trickle(bytes));
+        assert_eq!(read_test2(&mut d), Ok(Test2{bar: Some(999), foo: 111}));
+        assert_eq!(d.finish(), Ok(()));
+
+        let mut d = Decoder::new(b"d3:fooi1ee");
+        assert_eq!(read_test2(&mut d), Ok(Test2{bar: None, foo: 1}));
+
+        let e = read_test2(&mut Decoder::new(b"d3:bari1ee")).unwrap_err();
+        assert_eq!(*e.kind(), DecodeErrorKind::MissingField("foo".to_owned()));
+        assert_eq!(e.position(), Some(1));
+
+        let e = read_test2(&mut Decoder::new(b"d3:fooi1e3:bari2ee")).unwrap_err();
+        assert_eq!(*e.kind(), DecodeErrorKind::InvalidDict);
+        assert_eq!(e.position(), Some(9));
+
+        let e = read_test2(&mut Decoder::new(b"d3:foo1:xe")).unwrap_err();
+        assert_eq!(*e.kind(),
+            DecodeErrorKind::UnexpectedByte{expected: b'i', found: b'1'});
+        assert_eq!(e.path(), &[PathSegment::Key("foo".to_owned())]);
+        assert_eq!(e.position(), Some(6));
+
+        // Fields read more than once are counted once against limits
+        let mut d = Decoder::new(b"d3:fooli1ei2eee");
+        d.set_options(DecoderOptions::new().max_elements(3));
+        let res = d.read_struct_indexed(|d| {
+            let a: Vec<i32> = d.read_field("foo")?;
+            let b: Vec<i32> = d.read_field("foo")?;
+            Ok((a, b))
+        });
+        assert_eq!(res, Ok((vec![1, 2], vec![1, 2])));
+
+        let mut d = Decoder::new(b"d3:foo3:abce");
+        d.set_options(DecoderOptions::new().max_alloc(6));
+        let res = d.read_struct_indexed(|d| {
+            let a: String = d.read_field("foo")?;
+            let b: String = d.read_field("foo")?;
+            Ok((a, b))
+        });
+        assert_eq!(res, Ok(("abc".to_owned(), "abc".to_owned())));
+    }
+
+    #[test]
+    fn test_unknown_fields() {
+        let bytes = b"d1:ai1e3:bari2e1:ci3e3:fooi4e1:zd1:yi5eee";
+
+        let mut d = Decoder::new(bytes);
+        d.set_options(DecoderOptions::new().unknown_fields(UnknownFields::Collect));
+        assert_eq!(Test2::decode(&mut d), Ok(Test2{bar: Some(2), foo: 4}));
+
+        let skipped = d.skipped_fields().iter()
+            .map(|f| (f.key().as_bytes(), f.position()))
+            .collect::<Vec<_>>();
+        assert_eq!(skipped, [(&b"a"[..], 1), (&b"c"[..], 15), (&b"z"[..], 29)]);
+
+        let mut d = Decoder::new(bytes);
+        d.set_options(DecoderOptions::new().unknown_fields(UnknownFields::Deny));
+        let e = Test2::decode(&mut d).unwrap_err();
+        assert_eq!(*e.kind(), DecodeErrorKind::UnknownField("a".to_owned()));
+        assert_eq!(e.position(), Some(1));
+
+        let mut d = Decoder::new(b"d3:bari2e3:fooi4e1:zi0ee");
+        d.set_options(DecoderOptions::new().unknown_fields(UnknownFields::Deny));
+        let e = Test2::decode(&mut d).unwrap_err();
+        assert_eq!(*e.kind(), DecodeErrorKind::UnknownField("z".to_owned()));
+
+        // Missing optional fields are not unknown
+        let mut d = Decoder::new(b"d3:fooi4ee");
+        d.set_options(DecoderOptions::new().unknown_fields(UnknownFields::Deny));
+        assert_eq!(Test2::decode(&mut d), Ok(Test2{bar: None, foo: 4}));
+
+        let mut d = Decoder::new(b"d1:ai1e3:fooi4ee");
+        d.set_options(DecoderOptions::new().unknown_fields(UnknownFields::Collect));
+        let t = d.read_struct_indexed(|d| d.read_field::<i32>("foo")).unwrap();
+        assert_eq!(t, 4);
+        assert_eq!(d.skipped_fields().len(), 1);
+        assert_eq!(d.skipped_fields()[0].key().as_bytes(), b"a");
+    }
+
+    #[test]
+    fn test_struct_rest() {
+        fn read_test2(d: &mut Decoder) -> Result<(Test2, BTreeMap<ByteString, Value>), DecodeError> {
+            d.read_struct_with_rest(|d| {
+                Ok(Test2{
+                    bar: d.read_option("bar")?,
+                    foo: d.read_field("foo")?,
+                })
+            })
+        }
+
+        let bytes = b"d1:ai1e3:bari2e1:cli3ee3:fooi4e1:zd1:yi5eee";
+
+        let (t, rest) = read_test2(&mut Decoder::new(bytes)).unwrap();
+        assert_eq!(t, Test2{bar: Some(2), foo: 4});
+        assert_eq!(rest.keys().map(|k| k.as_bytes()).collect::<Vec<_>>(),
+            [&b"a"[..], b"c", b"z"]);
+        assert_eq!(rest[&b"c"[..]], Value::List(vec![Value::Integer(3)]));
+
+        let mut e = Encoder::new();
+        e.write_struct_with_rest(&rest, |e| {
+            e.write_option("bar", &t.bar)?;
+            e.write_field("foo", &t.foo)
+        }).unwrap();
+        assert_eq!(e.into_bytes().unwrap(), &bytes[..]);
+
+        let (t, rest) = read_test2(&mut Decoder::new(b"d3:fooi4ee")).unwrap();
+        assert_eq!(t, Test2{bar: None, foo: 4});
+        assert!(rest.is_empty());
+
+        // Fields written by the callable take precedence
+        let mut rest = BTreeMap::new();
+        rest.insert("foo", 1);
+        rest.insert("qux", 2);
+
+        let mut e = Encoder::new();
+        e.write_struct_with_rest(&rest, |e| e.write_field("foo", &0)).unwrap();
+        assert_eq!(e.into_bytes().unwrap(), b"d3:fooi0e3:quxi2ee");
+    }
+
+    #[test]
+    fn test_lenient() {
+        use super::{DecodeWarningKind, DuplicateKeys};
+
+        let bytes = b"d3:fooi007e3:bari-0e3:fooi2e1:ai-01ee";
+
+        assert_eq!(kind(decode::<Value>(bytes)), Err(DecodeErrorKind::InvalidNumber));
+        assert_eq!(kind(decode::<i64>(b"i-01e")), Err(DecodeErrorKind::InvalidNumber));
+
+        let mut d = Decoder::new(bytes);
+        d.set_options(DecoderOptions::new().lenient(true));
+        let v = Value::decode(&mut d).unwrap();
+
+        assert_eq!(encode(&v).unwrap(), b"d1:ai-1e3:bari0e3:fooi2ee");
+
+        for bytes in [&b"i0-5e"[..], b"i-0-5e", b"i00-7e", b"i-e", b"i--1e"] {
+            let mut d = Decoder::new(bytes);
+            d.set_options(DecoderOptions::new().lenient(true));
+            assert_eq!(kind(Value::decode(&mut d)), Err(DecodeErrorKind::InvalidNumber));
+            let mut d = Decoder::new(bytes);
+            d.set_options(DecoderOptions::new().lenient(true));
+            assert_eq!(kind(i64::decode(&mut d)), Err(DecodeErrorKind::InvalidNumber));
+        }
+        assert_eq!(d.warnings().iter()
+            .map(|w| (w.kind().clone(), w.position())).collect::<Vec<_>>(), [
+                (DecodeWarningKind::NonCanonicalNumber, 7),
+                (DecodeWarningKind::UnsortedKey("bar".to_owned()), 11),
+                (DecodeWarningKind::NonCanonicalNumber, 17),
+                (DecodeWarningKind::DuplicateKey("foo".to_owned()), 20),
+                (DecodeWarningKind::UnsortedKey("a".to_owned()), 28),
+                (DecodeWarningKind::NonCanonicalNumber, 32),
+            ]);
+
+        let mut d = Decoder::new(bytes);
+        d.set_options(DecoderOptions::new().lenient(true)
+            .duplicate_keys(DuplicateKeys::First));
+        let v = Value::decode(&mut d).unwrap();
+        assert_eq!(v.get("foo"), Some(&Value::Integer(7)));
+
+        // Fields of an unsorted struct are found in any order
+        let mut d = Decoder::new(b"d3:fooi1e3:bari2e3:fooi3ee");
+        d.set_options(DecoderOptions::new().lenient(true));
+        assert_eq!(Test2::decode(&mut d), Ok(Test2{bar: Some(2), foo: 3}));
+        assert_eq!(d.warnings().len(), 2);
+
+        let mut d = Decoder::new(b"d3:fooi1e1:zi0e3:bari2ee");
+        d.set_options(DecoderOptions::new().lenient(true));
+        let (t, rest) = d.read_struct_with_rest(|d| {
+            Ok(Test2{
+                bar: d.read_option("bar")?,
+                foo: d.read_field("foo")?,
+            })
+        }).unwrap();
+        assert_eq!(t, Test2{bar: Some(2), foo: 1});
+        assert_eq!(rest.get(&b"z"[..]), Some(&Value::Integer(0)));
+
+        // Values skipped and read again are warned about once
+        let mut d = Decoder::new(b"d1:ai01e3:fooi1ee");
+        d.set_options(DecoderOptions::new().lenient(true));
+        assert_eq!(Test2::decode(&mut d), Ok(Test2{bar: None, foo: 1}));
+        assert_eq!(d.warnings().len(), 1);
+
+        let mut d = Decoder::new(b"d3:fooi01ee");
+        d.set_options(DecoderOptions::new().lenient(true));
+        assert_eq!(d.read_struct_indexed(|d| d.read_field::<i32>("foo")), Ok(1));
+        assert_eq!(d.warnings().len(), 1);
+    }
+
+    #[test]
+    fn test_canonical() {
+        use super::{canonicalize, validate_canonical, DecodeWarningKind};
+
+        let bytes = b"d3:fooli1ei02ee3:bar03:baz3:quxd1:bi0e1:ai-0eee";
+        let warnings = validate_canonical(bytes).unwrap();
+
+        assert_eq!(warnings.iter()
+            .map(|w| (w.kind().clone(), w.position())).collect::<Vec<_>>(), [
+                (DecodeWarningKind::NonCanonicalNumber, 11),
+                (DecodeWarningKind::UnsortedKey("bar".to_owned()), 15),
+                (DecodeWarningKind::NonCanonicalNumber, 20),
+                (DecodeWarningKind::UnsortedKey("a".to_owned()), 38),
+                (DecodeWarningKind::NonCanonicalNumber, 42),
+            ]);
+        assert_eq!(canonicalize(bytes).unwrap(),
+            &b"d3:bar3:baz3:fooli1ei2ee3:quxd1:ai0e1:bi0eee"[..]);
+
+        let canonical = canonicalize(bytes).unwrap();
+        assert_eq!(validate_canonical(&canonical), Ok(vec![]));
+
+        let warnings = validate_canonical(b"i1ei2e").unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(*warnings[0].kind(), DecodeWarningKind::ExtraneousData);
+        assert_eq!(warnings[0].position(), 3);
+        assert_eq!(canonicalize(b"i1ei2e").unwrap(), b"i1e");
+
+        assert_eq!(kind(validate_canonical(b"d1:a")), Err(DecodeErrorKind::Eof));
+        assert_eq!(kind(validate_canonical(b"i00-7e")), Err(DecodeErrorKind::InvalidNumber));
+        assert_eq!(kind(validate_canonical(b"li0-5ee")), Err(DecodeErrorKind::InvalidNumber));
+        assert_eq!(kind(canonicalize(b"i0-5e")), Err(DecodeErrorKind::InvalidNumber));
+        assert_eq!(kind(canonicalize(b"d1:ai-0-5ee")), Err(DecodeErrorKind::InvalidNumber));
+    }
+
+    #[test]
+    fn test_struct_order() {
+        let mut e = Encoder::new();
+        assert_eq!(e.write_struct(|e| {
+            e.write_field("foo", &1)?;
+            e.write_field("bar", &2)
+        }), Err(EncodeError::UnsortedField("bar".to_owned())));
+
+        let mut e = Encoder::new();
+        assert_eq!(e.write_struct(|e| {
+            e.write_field("foo", &1)?;
+            e.write_option("foo", &Some(2))
+        }), Err(EncodeError::DuplicateField("foo".to_owned())));
+
+        // Order is tracked separately for nested structs
+        let mut e = Encoder::new();
+        e.write_struct(|e| {
+            e.write_field("foo", &Test2{bar: None, foo: 1})?;
+            e.write_field("zzz", &Test2{bar: Some(2), foo: 3})
+        }).unwrap();
+        assert_eq!(e.into_bytes().unwrap(), b"d3:food3:fooi1ee3:zzzd3:bari2e3:fooi3eee");
+    }
+
+    #[test]
+    fn test_hash_map() {
+        use std::collections::HashMap;
+
+        let mut m = HashMap::new();
+        m.insert("zulu".to_owned(), 1);
+        m.insert("alpha".to_owned(), 2);
+        m.insert("Bravo".to_owned(), 3);
+        m.insert("alp".to_owned(), 4);
+
+        let bytes = encode(&m).unwrap();
+        assert_eq!(bytes, &b"d5:Bravoi3e3:alpi4e5:alphai2e4:zului1ee"[..]);
+        assert_eq!(decode::<HashMap<String, i32>>(&bytes).unwrap(), m);
+
+        let mut m = HashMap::new();
+        m.insert(ByteString(vec![0xff]), "a");
+        m.insert(ByteString(vec![0x00, 0x01]), "b");
+
+        let bytes = encode(&m).unwrap();
+        assert_eq!(bytes, &b"d2:\x00\x011:b1:\xff1:ae"[..]);
+        let d = decode::<HashMap<ByteString, String>>(&bytes).unwrap();
+        assert_eq!(d[&[0xff][..]], "a");
+
+        let mut e = Encoder::new();
+        assert_eq!(e.write_unsorted_dict(vec![("b", 1), ("a", 2), ("b", 3)]),
+            Err(EncodeError::DuplicateField("b".to_owned())));
+    }
+
+    #[test]
+    fn test_raw_value() {
+        let bytes = b"d4:infod6:lengthi1e4:name3:fooe3:key5:valuee";
+
+        let m = decode::<BTreeMap<String, RawValue>>(bytes).unwrap();
+        assert_eq!(m["info"], RawValue(b"d6:lengthi1e4:name3:fooe".to_vec()));
+        assert_eq!(m["key"], RawValue(b"5:value".to_vec()));
+        assert_eq!(encode(&m).unwrap(), &bytes[..]);
+
+        let m: BTreeMap<&str, RawValueRef> = Decoder::new(bytes).read_dict().unwrap();
+        assert_eq!(m["info"], RawValueRef(b"d6:lengthi1e4:name3:fooe"));
+        assert_eq!(m["info"].to_owned(), RawValue(b"d6:lengthi1e4:name3:fooe".to_vec()));
+        assert_eq!(encode(&m).unwrap(), &bytes[..]);
+
+        let v = decode_from::<RawValue, _>(Trickle(b"li1ee")).unwrap();
+        assert_eq!(v, RawValue(b"li1ee".to_vec()));
+
+        assert_eq!(kind(decode::<RawValue>(b"d1:ai1e")), Err(DecodeErrorKind::Eof));
+
+        // Values are validated
+        for bytes in [&b"ie"[..], b"i--e", b"i-0e", b"i0-1e", b"i01e"] {
+            assert_eq!(kind(decode::<RawValue>(bytes)), Err(DecodeErrorKind::InvalidNumber));
+        }
+        assert_eq!(kind(decode::<RawValue>(b"d1:bi1e1:ai1ee")), Err(DecodeErrorKind::InvalidDict));
+        assert_eq!(kind(decode::<RawValue>(b"d1:ai1e1:ai1ee")), Err(DecodeErrorKind::InvalidDict));
+        assert_eq!(kind(decode::<RawValue>(b"di1ei1ee")), Err(DecodeErrorKind::InvalidNumber));
+        assert_eq!(kind(decode::<Value>(b"di1ei1ee")), Err(DecodeErrorKind::InvalidNumber));
+        assert_eq!(kind(Decoder::new(b"li1eie").read_raw_ref()),
+            Err(DecodeErrorKind::InvalidNumber));
+
+        let mut d = Decoder::new(b"d1:bi1e1:ai01ee");
+        d.set_options(DecoderOptions::new().lenient(true));
+        assert_eq!(RawValue::decode(&mut d), Ok(RawValue(b"d1:bi1e1:ai01ee".to_vec())));
+        assert_eq!(d.warnings().len(), 2);
+        assert_eq!(kind(Decoder::from_reader(&b"i1e"[..]).read_raw_ref()),
+            Err(DecodeErrorKind::CannotBorrow));
+    }
+
+    #[test]
+    fn test_walk() {
+        use std::borrow::Cow;
+        use super::Visitor;
+
+        #[derive(Default)]
+        struct Stats {
+            integers: usize,
+            bytes: usize,
+            keys: Vec<String>,
+            depth: usize,
+            max_depth: usize,
+        }
+
+        impl<'a> Visitor<'a> for Stats {
+            fn visit_integer(&mut self, _i: i64) -> Result<(), DecodeError> {
+                self.integers += 1;
+                Ok(())
+            }
+
+            fn visit_big_integer(&mut self, _s: &str) -> Result<(), DecodeError> {
+                self.integers += 1;
+                Ok(())
+            }
+
+            fn visit_bytes(&mut self, b: Cow<'a, [u8]>) -> Result<(), DecodeError> {
+                self.bytes += b.len();
+                Ok(())
+            }
+
+            fn begin_list(&mut self) -> Result<(), DecodeError> {
+                self.depth += 1;
+                self.max_depth = self.max_depth.max(self.depth);
+                Ok(())
+            }
+
+            fn end_list(&mut self) -> Result<(), DecodeError> {
+                self.depth -= 1;
+                Ok(())
+            }
+
+            fn key(&mut self, key: Cow<'a, [u8]>) -> Result<(), DecodeError> {
+                if &key[..] == b"bad" {
+                    return Err(DecodeError::new(DecodeErrorKind::Custom("bad key".to_owned())));
+                }
+                self.keys.push(String::from_utf8_lossy(&key).into_owned());
+                Ok(())
+            }
+        }
+
+        let mut stats = Stats::default();
+        let mut d = Decoder::new(b"d1:ai1e1:bll3:fooei99999999999999999999eee");
+        d.walk(&mut stats).unwrap();
+        d.finish().unwrap();
+
+        assert_eq!(stats.integers, 2);
+        assert_eq!(stats.bytes, 3);
+        assert_eq!(stats.keys, ["a", "b"]);
+        assert_eq!(stats.max_depth, 2);
+
+        let e = Decoder::new(b"d1:ald3:badi1eeee").walk(&mut Stats::default()).unwrap_err();
+        assert_eq!(*e.kind(), DecodeErrorKind::Custom("bad key".to_owned()));
+        assert_eq!(e.position(), Some(6));
+        assert_eq!(e.path(), &[PathSegment::Key("a".to_owned()), PathSegment::Index(0)]);
+
+        let e = Decoder::new(b"d1:bi1e1:ai2ee").walk(&mut Stats::default()).unwrap_err();
+        assert_eq!(*e.kind(), DecodeErrorKind::InvalidDict);
+        assert_eq!(e.position(), Some(7));
+
+        // A duplicate of a key other than the greatest is detected
+        use super::DecodeWarningKind;
+        let mut d = Decoder::new(b"d1:bi1e1:ai2e1:ai3e1:bi4ee");
+        d.set_options(DecoderOptions::new().lenient(true));
+        d.walk(&mut Stats::default()).unwrap();
+        assert_eq!(d.warnings().iter()
+            .map(|w| (w.kind().clone(), w.position())).collect::<Vec<_>>(), [
+                (DecodeWarningKind::UnsortedKey("a".to_owned()), 7),
+                (DecodeWarningKind::DuplicateKey("a".to_owned()), 13),
+                (DecodeWarningKind::DuplicateKey("b".to_owned()), 19),
+            ]);
+    }
+
+    #[test]
+    fn test_big_integer() {
+        assert_eq!(decode::<u128>(b"i340282366920938463463374607431768211455e").unwrap(),
+            u128::MAX);
+        assert_eq!(decode::<i128>(b"i-170141183460469231731687303715884105728e").unwrap(),
+            i128::MIN);
+        assert_eq!(encode(&u128::MAX).unwrap(),
+            &b"i340282366920938463463374607431768211455e"[..]);
+
+        let bytes = b"li1ei-123456789012345678901234567890ee";
+        let v = decode::<Value>(bytes).unwrap();
+        assert_eq!(v, Value::List(vec![
+            Value::Integer(1),
+            Value::BigInteger("-123456789012345678901234567890".to_owned()),
+        ]));
+        assert_eq!(encode(&v).unwrap(), &bytes[..]);
+
+        let v = decode_ref::<ValueRef>(b"i99999999999999999999e").unwrap();
+        assert_eq!(v.to_owned(), Value::BigInteger("99999999999999999999".to_owned()));
+
+        let e = decode::<Value>(b"i-e").unwrap_err();
+        assert_eq!(*e.kind(), DecodeErrorKind::InvalidNumber);
+        assert_eq!(e.position(), Some(1));
+
+        let mut e = Encoder::new();
+        assert_eq!(e.write_big_integer("012"),
+            Err(EncodeError::InvalidNumber("012".to_owned())));
+        assert_eq!(e.write_big_integer("1.5"),
+            Err(EncodeError::InvalidNumber("1.5".to_owned())));
+    }
+
+    #[derive(Debug, Eq, PartialEq)]
+    struct Borrowed<'a> {
+        name: &'a str,
+        pieces: &'a [u8],
+        tag: Cow<'a, ByteStr>,
+    }
+
+    impl<'a> DecodableRef<'a> for Borrowed<'a> {
+        fn decode_ref(d: &mut Decoder<'a>) -> Result<Borrowed<'a>, DecodeError> {
+            d.read_struct(|d| {
+                Ok(Borrowed{
+                    name: d.read_field("name")?,
+                    pieces: d.read_field("pieces")?,
+                    tag: d.read_field("tag")?,
+                })
+            })
+        }
+    }
+
+    #[test]
+    fn test_borrowed() {
+        let bytes = b"d4:name3:foo6:pieces3:\xaa\xbb\xcc3:tag3:bare";
+
+        let b: Borrowed = decode_ref(bytes).unwrap();
+        assert_eq!(b.name, "foo");
+        assert_eq!(b.pieces, b"\xaa\xbb\xcc");
+        assert_eq!(b.pieces.as_ptr(), bytes[22..].as_ptr());
+        assert_eq!(b.tag, Cow::Borrowed(ByteStr::from_bytes(b"bar")));
+
+        let mut d = Decoder::from_reader(&bytes[..]);
+        assert_eq!(kind(Borrowed::decode_ref(&mut d)),
+            Err(DecodeErrorKind::CannotBorrow));
+
+        let mut d = Decoder::from_reader(&b"3:foo"[..]);
+        let s = d.read_str_cow().unwrap();
+        assert!(match s { Cow::Owned(ref s) => s == "foo", _ => false });
     }
 
     #[test]
@@ -984,14 +3675,59 @@ mod test {
 
         assert_eq!(v, Value::Dict(
             vec![
-                ("alpha".to_string(), Value::Integer(123)),
-                ("beta".to_string(), Value::Bytes(b"\xaa\xbb\xcc".to_vec())),
-                ("gamma".to_string(), Value::String("ohai".to_string())),
-                ("lambda".to_string(), Value::List(vec![
+                ("alpha".into(), Value::Integer(123)),
+                ("beta".into(), Value::Bytes(b"\xaa\xbb\xcc".to_vec())),
+                ("gamma".into(), Value::String("ohai".to_string())),
+                ("lambda".into(), Value::List(vec![
                     Value::Integer(1),
                     Value::String("2".to_string()),
                 ])),
             ]
             .into_iter().collect::<BTreeMap<_, _>>()));
     }
+
+    #[test]
+    fn test_value_ref() {
+        let bytes = &b"\
+            d\
+            5:alphai123e\
+            4:beta3:\xaa\xbb\xcc\
+            6:lambdali1e1:2e\
+            e"[..];
+
+        let v: ValueRef = decode_ref(bytes).unwrap();
+
+        assert_eq!(v, ValueRef::Dict(
+            vec![
+                (ByteStr::from_bytes(b"alpha"), ValueRef::Integer(123)),
+                (ByteStr::from_bytes(b"beta"), ValueRef::Bytes(b"\xaa\xbb\xcc")),
+                (ByteStr::from_bytes(b"lambda"), ValueRef::List(vec![
+                    ValueRef::Integer(1),
+                    ValueRef::String("2"),
+                ])),
+            ]
+            .into_iter().collect::<BTreeMap<_, _>>()));
+
+        assert_eq!(v.to_owned(), decode::<Value>(bytes).unwrap());
+        assert_eq!(v.get("alpha"), Some(&ValueRef::Integer(123)));
+        assert_eq!(encode(&v).unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_byte_keys() {
+        let bytes = &b"d1:ai1e2:\xff\x00i2ee"[..];
+
+        let v = decode::<Value>(bytes).unwrap();
+        assert_eq!(v.get("a"), Some(&Value::Integer(1)));
+        assert_eq!(v.get(b"\xff\x00"), Some(&Value::Integer(2)));
+        assert_eq!(v.get("b"), None);
+        assert_eq!(encode(&v).unwrap(), bytes);
+
+        let m = decode::<BTreeMap<ByteString, i32>>(bytes).unwrap();
+        assert_eq!(m.get(&b"\xff\x00"[..]), Some(&2));
+        assert_eq!(encode(&m).unwrap(), bytes);
+
+        assert_eq!(kind(decode::<BTreeMap<String, i32>>(bytes)),
+            Err(DecodeErrorKind::InvalidUtf8));
+    }
 }
@@ -0,0 +1,1393 @@
+//! Typed representation of BitTorrent metainfo (`.torrent`) files.
+//!
+//! Version 1 (BEP 3), version 2 (BEP 52) and hybrid torrents are supported.
+//! A hybrid torrent describes its content in both forms, so that the fields
+//! of each version are present in the same `Info`.
+//!
+//! Fields not described by these specifications are retained in the `extra`
+//! field of each type and written back when encoding.
+//!
+//! The name and file paths of a v1 torrent are byte strings, as they need
+//! not be UTF-8 encoded; see `Metainfo::encoding`. The file tree of a v2
+//! torrent must be UTF-8 encoded.
+//!
+//! Metainfo for files on disk may be created using `MetainfoBuilder`.
+
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::slice::Chunks;
+use std::str;
+
+use digest::Digest;
+
+use {ByteStr, ByteString, Decodable, Decoder, DecodeError, DecodeErrorKind, DictKeys,
+    Encodable, Encoder, EncodeError, HashOf, PathSegment, Sha1, Sha256, Value, encode};
+
+/// Length of a SHA1 piece hash within `Info::pieces`.
+pub const PIECE_HASH_LEN: usize = 20;
+
+/// Length of a SHA-256 hash within a v2 file tree or piece layer.
+pub const MERKLE_HASH_LEN: usize = 32;
+
+/// Contents of a metainfo file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Metainfo {
+    /// URL of the tracker
+    pub announce: Option<String>,
+    /// Tiers of tracker URLs, as described in BEP 12
+    pub announce_list: Option<Vec<Vec<String>>>,
+    /// Free-form comment
+    pub comment: Option<String>,
+    /// Name and version of the program which created the file
+    pub created_by: Option<String>,
+    /// Creation time, in seconds since the Unix epoch
+    pub creation_date: Option<i64>,
+    /// Encoding of strings within the `info` dict
+    pub encoding: Option<String>,
+    /// Description of the torrent content
+    pub info: Info,
+    /// SHA1 hash of the encoded `info` dict, identifying a v1 or hybrid torrent
+    ///
+    /// When decoded, this is computed from the `info` dict exactly as it
+    /// appears in the input.
+    pub info_hash: HashOf<Sha1>,
+    /// SHA-256 hash of the encoded `info` dict, identifying a v2 or hybrid
+    /// torrent
+    pub info_hash_v2: Option<HashOf<Sha256>>,
+    /// Concatenated hashes of the pieces of each file in a v2 or hybrid
+    /// torrent, keyed by the `pieces root` of the file
+    pub piece_layers: Option<BTreeMap<ByteString, ByteString>>,
+    /// Additional fields
+    pub extra: BTreeMap<ByteString, Value>,
+}
+
+/// Description of the content of a torrent.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Info {
+    /// Suggested name of the file or directory; see `Info::name_str`
+    pub name: ByteString,
+    /// Number of bytes in each piece
+    pub piece_length: u64,
+    /// Concatenated SHA1 hashes of each piece of a v1 or hybrid torrent
+    pub pieces: Option<Vec<u8>>,
+    /// Files contained in a v1 or hybrid torrent
+    pub files: Option<Files>,
+    /// Version of the metainfo format; `2` for a v2 or hybrid torrent
+    pub meta_version: Option<u64>,
+    /// Files contained in a v2 or hybrid torrent
+    pub file_tree: Option<FileTree>,
+    /// Value of the `private` field; see `Info::is_private`
+    pub private: Option<i64>,
+    /// Additional fields
+    pub extra: BTreeMap<ByteString, Value>,
+}
+
+/// Files contained in a torrent.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Files {
+    /// Single file named by `Info::name`, with the given length
+    Single(u64),
+    /// Files within a directory named by `Info::name`
+    Multiple(Vec<File>),
+}
+
+/// File or directory within the file tree of a v2 torrent.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FileTree {
+    /// File
+    File(TreeFile),
+    /// Directory, containing named files and directories
+    Directory(BTreeMap<String, FileTree>),
+}
+
+/// File within the file tree of a v2 torrent.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TreeFile {
+    /// Length of the file, in bytes
+    pub length: u64,
+    /// Root of the Merkle tree of the file content; absent if the file is empty
+    pub pieces_root: Option<[u8; MERKLE_HASH_LEN]>,
+    /// Additional fields
+    pub extra: BTreeMap<ByteString, Value>,
+}
+
+/// File within a multi-file v1 torrent.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct File {
+    /// Length of the file, in bytes
+    pub length: u64,
+    /// Path components of the file, relative to the torrent directory;
+    /// see `File::path_str`
+    pub path: Vec<ByteString>,
+    /// Additional fields
+    pub extra: BTreeMap<ByteString, Value>,
+}
+
+impl Metainfo {
+    /// Returns an iterator over the hashes of the pieces of the file
+    /// with the given `pieces root`, if present in `piece_layers`.
+    pub fn piece_layer(&self, pieces_root: &[u8]) -> Option<Chunks<'_, u8>> {
+        self.piece_layers.as_ref()
+            .and_then(|layers| layers.get(pieces_root))
+            .map(|layer| layer.0.chunks(MERKLE_HASH_LEN))
+    }
+}
+
+impl Info {
+    /// Returns the SHA1 hash of the encoded `info` dict.
+    pub fn hash(&self) -> Result<HashOf<Sha1>, EncodeError> {
+        Ok(HashOf(Sha1::digest(encode(self)?)))
+    }
+
+    /// Returns the SHA-256 hash of the encoded `info` dict.
+    pub fn hash_v2(&self) -> Result<HashOf<Sha256>, EncodeError> {
+        Ok(HashOf(Sha256::digest(encode(self)?)))
+    }
+
+    /// Returns `name` as a string, if it is valid UTF-8.
+    pub fn name_str(&self) -> Option<&str> {
+        str::from_utf8(&self.name.0).ok()
+    }
+
+    /// Returns whether peers should be obtained only from the listed trackers.
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    /// Returns whether the torrent is described in the v1 format.
+    pub fn is_v1(&self) -> bool {
+        self.pieces.is_some()
+    }
+
+    /// Returns whether the torrent is described in the v2 format.
+    pub fn is_v2(&self) -> bool {
+        self.file_tree.is_some()
+    }
+
+    /// Returns whether the torrent is described in both formats.
+    pub fn is_hybrid(&self) -> bool {
+        self.is_v1() && self.is_v2()
+    }
+
+    /// Returns an iterator over the SHA1 hash of each piece.
+    ///
+    /// The iterator is empty for a v2 torrent.
+    pub fn piece_hashes(&self) -> Chunks<'_, u8> {
+        self.pieces.as_deref().unwrap_or(&[]).chunks(PIECE_HASH_LEN)
+    }
+
+    /// Returns the total length of all files, in bytes.
+    ///
+    /// For a v2 or hybrid torrent, this is computed from the file tree,
+    /// excluding any padding files of the v1 description.
+    pub fn total_length(&self) -> u64 {
+        if let Some(ref tree) = self.file_tree {
+            return tree.files().iter().map(|&(_, f)| f.length).sum();
+        }
+
+        match self.files {
+            Some(Files::Single(length)) => length,
+            Some(Files::Multiple(ref files)) => files.iter().map(|f| f.length).sum(),
+            None => 0,
+        }
+    }
+}
+
+impl File {
+    /// Returns the components of `path` as strings, if all are valid UTF-8.
+    pub fn path_str(&self) -> Option<Vec<&str>> {
+        self.path.iter().map(|c| str::from_utf8(&c.0).ok()).collect()
+    }
+}
+
+impl FileTree {
+    /// Returns all files within the tree, in order, with their paths.
+    pub fn files(&self) -> Vec<(Vec<&str>, &TreeFile)> {
+        let mut files = Vec::new();
+        self.collect_files(&mut Vec::new(), &mut files);
+        files
+    }
+
+    fn collect_files<'a>(&'a self, path: &mut Vec<&'a str>,
+            files: &mut Vec<(Vec<&'a str>, &'a TreeFile)>) {
+        match *self {
+            FileTree::File(ref f) => files.push((path.clone(), f)),
+            FileTree::Directory(ref entries) => {
+                for (name, entry) in entries {
+                    path.push(name);
+                    entry.collect_files(path, files);
+                    path.pop();
+                }
+            }
+        }
+    }
+}
+
+impl Decodable for Metainfo {
+    fn decode(d: &mut Decoder) -> Result<Metainfo, DecodeError> {
+        let (mut m, extra) = d.read_struct_with_rest(|d| {
+            let announce = d.read_option("announce")?;
+            let announce_list = d.read_option("announce-list")?;
+            let comment = d.read_option("comment")?;
+            let created_by = d.read_option("created by")?;
+            let creation_date = d.read_option("creation date")?;
+            let encoding = d.read_option("encoding")?;
+            let info: HashedInfo = d.read_field("info")?;
+            let piece_layers = d.read_option("piece layers")?;
+
+            Ok(Metainfo{
+                announce,
+                announce_list,
+                comment,
+                created_by,
+                creation_date,
+                encoding,
+                info: info.info,
+                info_hash: info.sha1,
+                info_hash_v2: info.sha256,
+                piece_layers,
+                extra: BTreeMap::new(),
+            })
+        })?;
+
+        m.extra = extra;
+        Ok(m)
+    }
+}
+
+impl Encodable for Metainfo {
+    fn encode(&self, e: &mut Encoder) -> Result<(), EncodeError> {
+        e.write_struct_with_rest(&self.extra, |e| {
+            e.write_option("announce", &self.announce)?;
+            e.write_option("announce-list", &self.announce_list)?;
+            e.write_option("comment", &self.comment)?;
+            e.write_option("created by", &self.created_by)?;
+            e.write_option("creation date", &self.creation_date)?;
+            e.write_option("encoding", &self.encoding)?;
+            e.write_field("info", &self.info)?;
+            e.write_option("piece layers", &self.piece_layers)
+        })
+    }
+}
+
+impl Decodable for Info {
+    fn decode(d: &mut Decoder) -> Result<Info, DecodeError> {
+        let pos = d.position();
+
+        let (mut info, extra) = d.read_struct_with_rest(|d| {
+            let file_tree = d.read_option("file tree")?;
+            let files = d.read_option("files")?;
+            let length = d.read_option("length")?;
+            let meta_version = d.read_option("meta version")?;
+            let name = d.read_field("name")?;
+            let piece_length = d.read_field("piece length")?;
+            let pieces = d.read_option::<ByteString>("pieces")?.map(|p| p.0);
+            let private = d.read_option("private")?;
+
+            let files = match (files, length) {
+                (Some(files), None) => Some(Files::Multiple(files)),
+                (None, Some(length)) => Some(Files::Single(length)),
+                (None, None) => None,
+                (Some(_), Some(_)) => return Err(invalid(
+                    "both `files` and `length` present".to_owned())),
+            };
+
+            match (&files, &pieces) {
+                (&Some(_), &None) => return Err(missing("pieces")),
+                (&None, &Some(_)) => return Err(missing("length")),
+                _ => ()
+            }
+
+            match (meta_version, &file_tree) {
+                (None, &None) | (Some(2), &Some(_)) => (),
+                (Some(2), &None) => return Err(missing("file tree")),
+                (None, &Some(_)) => return Err(missing("meta version")),
+                (Some(v), _) => return Err(invalid(
+                    format!("unsupported meta version {}", v))),
+            }
+
+            match pieces {
+                Some(ref pieces) if pieces.len() % PIECE_HASH_LEN != 0 => {
+                    return Err(invalid(format!(
+                        "length of `pieces` is not a multiple of {}", PIECE_HASH_LEN)));
+                }
+                None if file_tree.is_none() => return Err(missing("pieces")),
+                _ => ()
+            }
+
+            Ok(Info{
+                name,
+                piece_length,
+                pieces,
+                files,
+                meta_version,
+                file_tree,
+                private,
+                extra: BTreeMap::new(),
+            })
+        }).map_err(|e| e.at(pos))?;
+
+        info.extra = extra;
+        Ok(info)
+    }
+}
+
+impl Encodable for Info {
+    fn encode(&self, e: &mut Encoder) -> Result<(), EncodeError> {
+        e.write_struct_with_rest(&self.extra, |e| {
+            e.write_option("file tree", &self.file_tree)?;
+            match self.files {
+                Some(Files::Single(ref length)) => e.write_field("length", length)?,
+                Some(Files::Multiple(ref files)) => e.write_field("files", files)?,
+                None => ()
+            }
+            e.write_option("meta version", &self.meta_version)?;
+            e.write_field("name", &self.name)?;
+            e.write_field("piece length", &self.piece_length)?;
+            if let Some(ref pieces) = self.pieces {
+                e.write_field("pieces", ByteStr::from_bytes(pieces))?;
+            }
+            e.write_option("private", &self.private)
+        })
+    }
+}
+
+impl Decodable for File {
+    fn decode(d: &mut Decoder) -> Result<File, DecodeError> {
+        let ((length, path), extra) = d.read_struct_with_rest(|d| {
+            Ok((d.read_field("length")?, d.read_field("path")?))
+        })?;
+
+        Ok(File{length, path, extra})
+    }
+}
+
+impl Encodable for File {
+    fn encode(&self, e: &mut Encoder) -> Result<(), EncodeError> {
+        e.write_struct_with_rest(&self.extra, |e| {
+            e.write_field("length", &self.length)?;
+            e.write_field("path", &self.path)
+        })
+    }
+}
+
+impl Decodable for FileTree {
+    fn decode(d: &mut Decoder) -> Result<FileTree, DecodeError> {
+        let pos = d.position();
+        d.expect(b'd')?;
+
+        let mut file = None;
+        let mut entries = BTreeMap::new();
+
+        d.nest(|d| {
+            let mut keys = DictKeys::new();
+
+            while d.peek_byte()? != b'e' {
+                d.count_element()?;
+                let pos = d.position();
+                let name = d.read_str()?;
+                let keep = keys.check(d, name.as_bytes(), pos)?;
+
+                // A file is represented by a dict containing an empty key
+                if name.is_empty() {
+                    let f: TreeFile = d.read_nested(|| PathSegment::Key(String::new()))?;
+                    if keep {
+                        file = Some(f);
+                    }
+                } else {
+                    let entry: FileTree = d.read_nested(|| PathSegment::Key(name.clone()))?;
+                    if keep {
+                        entries.insert(name, entry);
+                    }
+                }
+            }
+            Ok(())
+        })?;
+
+        d.expect(b'e')?;
+
+        match file {
+            Some(f) if entries.is_empty() => Ok(FileTree::File(f)),
+            Some(_) => Err(invalid("file tree entry is both a file and a directory".to_owned())
+                .at(pos)),
+            None => Ok(FileTree::Directory(entries)),
+        }
+    }
+}
+
+impl Encodable for FileTree {
+    fn encode(&self, e: &mut Encoder) -> Result<(), EncodeError> {
+        match *self {
+            FileTree::File(ref f) => e.write_struct(|e| e.write_field("", f)),
+            FileTree::Directory(ref entries) => e.write_dict(entries),
+        }
+    }
+}
+
+impl Decodable for TreeFile {
+    fn decode(d: &mut Decoder) -> Result<TreeFile, DecodeError> {
+        let pos = d.position();
+
+        let ((length, root), extra) = d.read_struct_with_rest(|d| {
+            Ok((d.read_field("length")?, d.read_option::<ByteString>("pieces root")?))
+        })?;
+
+        let pieces_root = match root {
+            Some(ref root) if root.0.len() != MERKLE_HASH_LEN => {
+                return Err(invalid(format!(
+                    "length of `pieces root` is not {}", MERKLE_HASH_LEN)).at(pos));
+            }
+            Some(root) => {
+                let mut buf = [0; MERKLE_HASH_LEN];
+                buf.copy_from_slice(&root.0);
+                Some(buf)
+            }
+            None => None
+        };
+
+        Ok(TreeFile{length, pieces_root, extra})
+    }
+}
+
+impl Encodable for TreeFile {
+    fn encode(&self, e: &mut Encoder) -> Result<(), EncodeError> {
+        e.write_struct_with_rest(&self.extra, |e| {
+            e.write_field("length", &self.length)?;
+            if let Some(ref root) = self.pieces_root {
+                e.write_field("pieces root", ByteStr::from_bytes(root))?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Returns an error for a required field which is not present.
+fn missing(name: &str) -> DecodeError {
+    DecodeError::new(DecodeErrorKind::MissingField(name.to_owned()))
+}
+
+/// Returns an error for a value which does not conform to the specification.
+fn invalid(msg: String) -> DecodeError {
+    DecodeError::new(DecodeErrorKind::Custom(msg))
+}
+
+/// Decoded info dictionary accompanied by the hashes of its encoded
+/// representation.
+struct HashedInfo {
+    info: Info,
+    sha1: HashOf<Sha1>,
+    /// Present only for a v2 or hybrid torrent
+    sha256: Option<HashOf<Sha256>>,
+}
+
+impl Decodable for HashedInfo {
+    fn decode(d: &mut Decoder) -> Result<HashedInfo, DecodeError> {
+        d.with_pin(|d| {
+            let start = d.position();
+            let info = Info::decode(d)?;
+            let end = d.position();
+
+            d.set_position(start);
+            let raw = d.peek_bytes((end - start) as usize)?;
+            let sha1 = HashOf(Sha1::digest(raw));
+            let sha256 = if info.is_v2() { Some(HashOf(Sha256::digest(raw))) } else { None };
+            d.set_position(end);
+
+            Ok(HashedInfo{info, sha1, sha256})
+        })
+    }
+}
+
+/// Format in which a `MetainfoBuilder` describes a torrent.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Version {
+    /// BEP 3 format, with SHA1 piece hashes
+    V1,
+    /// BEP 52 format, with a file tree and SHA-256 Merkle trees
+    V2,
+    /// Both formats; files of the v1 description are aligned to piece
+    /// boundaries by padding files, as described in BEP 47
+    Hybrid,
+}
+
+/// Size of a block hashed to form the leaves of the Merkle tree of a v2 file.
+pub const BLOCK_LEN: u64 = 16 * 1024;
+
+/// Default number of bytes in each piece of a torrent built by
+/// `MetainfoBuilder`.
+pub const DEFAULT_PIECE_LENGTH: u64 = 256 * 1024;
+
+/// Constructs the metainfo of a torrent from a file or directory on disk.
+#[derive(Clone, Debug)]
+pub struct MetainfoBuilder {
+    version: Version,
+    piece_length: u64,
+    name: Option<String>,
+    announce: Option<String>,
+    announce_list: Option<Vec<Vec<String>>>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: Option<i64>,
+    private: bool,
+}
+
+/// Represents an error in building metainfo with `MetainfoBuilder`.
+#[derive(Debug)]
+pub enum BuildError {
+    /// Error reading files from disk
+    Io(io::Error),
+    /// Piece length which is zero or, for a v2 or hybrid torrent,
+    /// not a power of two of at least `BLOCK_LEN`
+    InvalidPieceLength(u64),
+    /// File name which is not valid UTF-8, in a v2 or hybrid torrent,
+    /// or which cannot be represented as bytes on this platform
+    InvalidName(PathBuf),
+    /// Symbolic link within a directory, which is not followed
+    Symlink(PathBuf),
+    /// Directory containing no files
+    NoFiles,
+    /// Error encoding the metainfo
+    Encode(EncodeError),
+}
+
+impl From<io::Error> for BuildError {
+    fn from(e: io::Error) -> BuildError {
+        BuildError::Io(e)
+    }
+}
+
+impl From<EncodeError> for BuildError {
+    fn from(e: EncodeError) -> BuildError {
+        BuildError::Encode(e)
+    }
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BuildError::Io(ref e) => write!(f, "i/o error: {}", e),
+            BuildError::InvalidPieceLength(n) => write!(f, "invalid piece length {}", n),
+            BuildError::InvalidName(ref path) =>
+                write!(f, "file name is not valid UTF-8: {}", path.display()),
+            BuildError::Symlink(ref path) =>
+                write!(f, "symbolic link in directory: {}", path.display()),
+            BuildError::NoFiles => f.write_str("no files found"),
+            BuildError::Encode(ref e) => fmt::Display::fmt(e, f),
+        }
+    }
+}
+
+impl Error for BuildError {}
+
+impl MetainfoBuilder {
+    /// Returns a new `MetainfoBuilder` for a v1 torrent,
+    /// with the default piece length.
+    pub fn new() -> MetainfoBuilder {
+        MetainfoBuilder{
+            version: Version::V1,
+            piece_length: DEFAULT_PIECE_LENGTH,
+            name: None,
+            announce: None,
+            announce_list: None,
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            private: false,
+        }
+    }
+
+    /// Sets the format in which the torrent is described.
+    pub fn version(mut self, version: Version) -> MetainfoBuilder {
+        self.version = version;
+        self
+    }
+
+    /// Sets the number of bytes in each piece.
+    ///
+    /// For a v2 or hybrid torrent, this must be a power of two
+    /// of at least `BLOCK_LEN`.
+    pub fn piece_length(mut self, n: u64) -> MetainfoBuilder {
+        self.piece_length = n;
+        self
+    }
+
+    /// Sets the name of the torrent.
+    ///
+    /// By default, this is the name of the file or directory.
+    pub fn name(mut self, name: &str) -> MetainfoBuilder {
+        self.name = Some(name.to_owned());
+        self
+    }
+
+    /// Sets the URL of the tracker.
+    pub fn announce(mut self, url: &str) -> MetainfoBuilder {
+        self.announce = Some(url.to_owned());
+        self
+    }
+
+    /// Sets the tiers of tracker URLs.
+    pub fn announce_list(mut self, tiers: Vec<Vec<String>>) -> MetainfoBuilder {
+        self.announce_list = Some(tiers);
+        self
+    }
+
+    /// Sets the free-form comment.
+    pub fn comment(mut self, comment: &str) -> MetainfoBuilder {
+        self.comment = Some(comment.to_owned());
+        self
+    }
+
+    /// Sets the name and version of the creating program.
+    pub fn created_by(mut self, created_by: &str) -> MetainfoBuilder {
+        self.created_by = Some(created_by.to_owned());
+        self
+    }
+
+    /// Sets the creation time, in seconds since the Unix epoch.
+    pub fn creation_date(mut self, date: i64) -> MetainfoBuilder {
+        self.creation_date = Some(date);
+        self
+    }
+
+    /// Sets whether peers should be obtained only from the listed trackers.
+    pub fn private(mut self, private: bool) -> MetainfoBuilder {
+        self.private = private;
+        self
+    }
+
+    /// Builds the metainfo of the file or directory at `path`,
+    /// reading and hashing its contents.
+    ///
+    /// The files of a directory are included recursively,
+    /// in order of their paths. Symbolic links within a directory
+    /// are not followed, and result in an error.
+    pub fn build<P: AsRef<Path>>(&self, path: P) -> Result<Metainfo, BuildError> {
+        let path = path.as_ref();
+        let v1 = self.version != Version::V2;
+        let v2 = self.version != Version::V1;
+
+        if self.piece_length == 0 ||
+                (v2 && (!self.piece_length.is_power_of_two() || self.piece_length < BLOCK_LEN)) {
+            return Err(BuildError::InvalidPieceLength(self.piece_length));
+        }
+
+        let name = match self.name {
+            Some(ref name) => name.clone().into_bytes(),
+            None => {
+                let path = fs::canonicalize(path)?;
+                match path.file_name().and_then(name_bytes) {
+                    Some(name) => name,
+                    None => return Err(BuildError::InvalidName(path)),
+                }
+            }
+        };
+
+        let single = !fs::metadata(path)?.is_dir();
+        let mut paths = Vec::new();
+
+        if single {
+            paths.push((vec![name.clone()], path.to_owned()));
+        } else {
+            collect_files(path, &mut Vec::new(), &mut paths)?;
+            if paths.is_empty() {
+                return Err(BuildError::NoFiles);
+            }
+        }
+
+        // Names within a v2 file tree are UTF-8 strings,
+        // whereas v1 names may be arbitrary bytes.
+        let tree_paths = if v2 {
+            if str::from_utf8(&name).is_err() {
+                return Err(BuildError::InvalidName(path.to_owned()));
+            }
+            paths.iter().map(|(components, file_path)| {
+                components.iter()
+                    .map(|c| String::from_utf8(c.clone())
+                        .map_err(|_| BuildError::InvalidName(file_path.clone())))
+                    .collect()
+            }).collect::<Result<Vec<Vec<String>>, _>>()?
+        } else {
+            Vec::new()
+        };
+
+        let mut hasher = PieceHasher::new(self.piece_length);
+        let mut files = Vec::new();
+        let mut tree = BTreeMap::new();
+        let mut layers = BTreeMap::new();
+
+        for (i, (components, file_path)) in paths.iter().enumerate() {
+            let HashedFile{length, pieces_root, piece_layer} =
+                hasher.add_file(file_path, v1, v2)?;
+
+            if v1 {
+                files.push(File{
+                    length,
+                    path: if single {
+                        Vec::new()
+                    } else {
+                        components.iter().map(|c| ByteString(c.clone())).collect()
+                    },
+                    extra: BTreeMap::new(),
+                });
+
+                if v2 && i + 1 < paths.len() {
+                    if let Some(pad) = hasher.pad() {
+                        let mut extra = BTreeMap::new();
+                        extra.insert(ByteString::from("attr"), Value::String("p".to_owned()));
+                        files.push(File{
+                            length: pad,
+                            path: vec![ByteString::from(".pad"),
+                                ByteString::from(pad.to_string())],
+                            extra,
+                        });
+                    }
+                }
+            }
+
+            if v2 {
+                if let (Some(root), Some(layer)) = (pieces_root, piece_layer) {
+                    layers.insert(ByteString(root.to_vec()), ByteString(layer));
+                }
+                insert_file(&mut tree, &tree_paths[i], TreeFile{
+                    length,
+                    pieces_root,
+                    extra: BTreeMap::new(),
+                });
+            }
+        }
+
+        let pieces = hasher.finish();
+
+        let files = if !v1 {
+            None
+        } else if single {
+            Some(Files::Single(files[0].length))
+        } else {
+            Some(Files::Multiple(files))
+        };
+
+        let info = Info{
+            name: ByteString(name),
+            piece_length: self.piece_length,
+            pieces: if v1 { Some(pieces) } else { None },
+            files,
+            meta_version: if v2 { Some(2) } else { None },
+            file_tree: if v2 { Some(FileTree::Directory(tree)) } else { None },
+            private: if self.private { Some(1) } else { None },
+            extra: BTreeMap::new(),
+        };
+
+        Ok(Metainfo{
+            announce: self.announce.clone(),
+            announce_list: self.announce_list.clone(),
+            comment: self.comment.clone(),
+            created_by: self.created_by.clone(),
+            creation_date: self.creation_date,
+            encoding: None,
+            info_hash: info.hash()?,
+            info_hash_v2: if v2 { Some(info.hash_v2()?) } else { None },
+            piece_layers: if v2 { Some(layers) } else { None },
+            info,
+            extra: BTreeMap::new(),
+        })
+    }
+
+    /// Builds the metainfo of the file or directory at `path`,
+    /// returning its encoded representation.
+    pub fn build_bytes<P: AsRef<Path>>(&self, path: P) -> Result<Vec<u8>, BuildError> {
+        Ok(encode(&self.build(path)?)?)
+    }
+}
+
+impl Default for MetainfoBuilder {
+    fn default() -> MetainfoBuilder {
+        MetainfoBuilder::new()
+    }
+}
+
+/// Adds the files within the directory `dir` to `files`, recursively,
+/// along with their path components, in order.
+fn collect_files(dir: &Path, components: &mut Vec<Vec<u8>>,
+        files: &mut Vec<(Vec<Vec<u8>>, PathBuf)>) -> Result<(), BuildError> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let name = match name_bytes(&entry.file_name()) {
+            Some(name) => name,
+            None => return Err(BuildError::InvalidName(path)),
+        };
+
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            return Err(BuildError::Symlink(path));
+        }
+
+        components.push(name);
+        if file_type.is_dir() {
+            collect_files(&path, components, files)?;
+        } else {
+            files.push((components.clone(), path));
+        }
+        components.pop();
+    }
+
+    Ok(())
+}
+
+/// Returns the bytes of a file name, as they are stored in v1 metainfo.
+#[cfg(unix)]
+fn name_bytes(name: &OsStr) -> Option<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(name.as_bytes().to_vec())
+}
+
+/// Returns the bytes of a file name, as they are stored in v1 metainfo.
+#[cfg(not(unix))]
+fn name_bytes(name: &OsStr) -> Option<Vec<u8>> {
+    name.to_str().map(|name| name.as_bytes().to_vec())
+}
+
+/// Inserts a file into a file tree at the given path.
+fn insert_file(tree: &mut BTreeMap<String, FileTree>, path: &[String], file: TreeFile) {
+    if path.len() == 1 {
+        tree.insert(path[0].clone(), FileTree::File(file));
+        return;
+    }
+
+    let entry = tree.entry(path[0].clone())
+        .or_insert_with(|| FileTree::Directory(BTreeMap::new()));
+
+    if let FileTree::Directory(ref mut entries) = *entry {
+        insert_file(entries, &path[1..], file);
+    }
+}
+
+/// Computes the v1 piece hashes of a series of files,
+/// and the v2 Merkle tree of each file.
+struct PieceHasher {
+    piece_length: u64,
+    /// Hash of the current piece
+    piece: Sha1,
+    /// Number of bytes in the current piece
+    piece_len: u64,
+    /// Concatenated hashes of completed pieces
+    pieces: Vec<u8>,
+}
+
+type MerkleHash = [u8; MERKLE_HASH_LEN];
+
+/// Result of reading a file with `PieceHasher::add_file`.
+struct HashedFile {
+    length: u64,
+    /// Root of the Merkle tree, if computed and the file is not empty
+    pieces_root: Option<MerkleHash>,
+    /// Piece layer of the Merkle tree, if the file is larger than one piece
+    piece_layer: Option<Vec<u8>>,
+}
+
+impl PieceHasher {
+    fn new(piece_length: u64) -> PieceHasher {
+        PieceHasher{
+            piece_length,
+            piece: Sha1::new(),
+            piece_len: 0,
+            pieces: Vec::new(),
+        }
+    }
+
+    /// Reads the file at `path`, adding its content to the v1 pieces if `v1`
+    /// and computing its Merkle tree if `v2`.
+    ///
+    fn add_file(&mut self, path: &Path, v1: bool, v2: bool)
+            -> Result<HashedFile, BuildError> {
+        let mut file = fs::File::open(path)?;
+        let mut buf = vec![0; BLOCK_LEN as usize];
+        let mut leaves = Vec::new();
+        let mut length = 0;
+
+        loop {
+            let n = read_block(&mut file, &mut buf)?;
+            if n == 0 {
+                break;
+            }
+            length += n as u64;
+
+            if v1 {
+                self.update(&buf[..n]);
+            }
+            if v2 {
+                leaves.push(sha256(&[&buf[..n]]));
+            }
+        }
+
+        if !v2 || leaves.is_empty() {
+            return Ok(HashedFile{length, pieces_root: None, piece_layer: None});
+        }
+
+        let (root, layer) = merkle_tree(leaves, self.piece_length / BLOCK_LEN);
+        let pieces = length.div_ceil(self.piece_length) as usize;
+
+        let piece_layer = if length > self.piece_length {
+            Some(layer[..pieces].concat())
+        } else {
+            None
+        };
+
+        Ok(HashedFile{length, pieces_root: Some(root), piece_layer})
+    }
+
+    /// Adds data to the current piece, completing pieces as they are filled.
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let n = min(self.piece_length - self.piece_len, data.len() as u64) as usize;
+            self.piece.update(&data[..n]);
+            self.piece_len += n as u64;
+            data = &data[n..];
+
+            if self.piece_len == self.piece_length {
+                self.pieces.extend_from_slice(&self.piece.finalize_reset());
+                self.piece_len = 0;
+            }
+        }
+    }
+
+    /// Fills the remainder of the current piece with zeros,
+    /// returning the number of bytes added.
+    fn pad(&mut self) -> Option<u64> {
+        if self.piece_len == 0 {
+            return None;
+        }
+
+        let pad = self.piece_length - self.piece_len;
+        self.update(&vec![0; pad as usize]);
+        Some(pad)
+    }
+
+    /// Completes the final piece, returning the concatenated piece hashes.
+    fn finish(mut self) -> Vec<u8> {
+        if self.piece_len != 0 {
+            self.pieces.extend_from_slice(&self.piece.finalize());
+        }
+        self.pieces
+    }
+}
+
+/// Reads from `r` until `buf` is full or end-of-file is reached,
+/// returning the number of bytes read.
+fn read_block<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+
+    while n < buf.len() {
+        match r.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(k) => n += k,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(n)
+}
+
+/// Returns the SHA-256 hash of the concatenation of `parts`.
+fn sha256(parts: &[&[u8]]) -> MerkleHash {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+
+    let mut hash = [0; MERKLE_HASH_LEN];
+    hash.copy_from_slice(&hasher.finalize());
+    hash
+}
+
+/// Computes the Merkle tree of a file from the hashes of its blocks,
+/// returning its root and the layer of nodes covering `blocks_per_piece`
+/// blocks each.
+///
+/// The leaves are padded with zero hashes to a power of two. If the tree
+/// is smaller than one piece, the returned layer is empty.
+fn merkle_tree(mut level: Vec<MerkleHash>, blocks_per_piece: u64)
+        -> (MerkleHash, Vec<MerkleHash>) {
+    level.resize(level.len().next_power_of_two(), [0; MERKLE_HASH_LEN]);
+
+    let mut span = 1;
+    let mut layer = Vec::new();
+
+    loop {
+        if span == blocks_per_piece {
+            layer = level.clone();
+        }
+        if level.len() == 1 {
+            return (level[0], layer);
+        }
+
+        level = level.chunks(2).map(|pair| sha256(&[&pair[0], &pair[1]])).collect();
+        span *= 2;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use digest::Digest;
+    use super::{BuildError, FileTree, Files, Metainfo, MetainfoBuilder, Version};
+    use {decode, decode_from, encode, DecodeErrorKind, HashOf, PathSegment, Sha1, Sha256, Value};
+    use {ByteString, Decodable, Decoder, DecoderOptions, DuplicateKeys};
+
+    const INFO: &[u8] = b"d\
+        5:filesl\
+            d6:lengthi3e4:pathl1:a1:bee\
+            d6:lengthi5e4:pathl1:cee\
+        e\
+        4:name3:foo\
+        12:piece lengthi16384e\
+        6:pieces20:aaaaaaaaaaaaaaaaaaaa\
+        7:privatei1e\
+        6:source4:barze";
+
+    const INFO_V2: &[u8] = b"d\
+        9:file treed\
+            1:ad1:bd0:d6:lengthi0eeee\
+            3:food0:d6:lengthi5e11:pieces root32:rrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrree\
+        e\
+        12:meta versioni2e\
+        4:name3:foo\
+        12:piece lengthi16384e\
+        e";
+
+    const INFO_HYBRID: &[u8] = b"d\
+        9:file treed3:food0:d6:lengthi5e11:pieces root32:rrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrreee\
+        6:lengthi5e\
+        12:meta versioni2e\
+        4:name3:foo\
+        12:piece lengthi16384e\
+        6:pieces20:aaaaaaaaaaaaaaaaaaaa\
+        e";
+
+    fn torrent(info: &[u8]) -> Vec<u8> {
+        let mut bytes = b"d8:announce14:http://tracker13:announce-listll14:http://trackerel3:udpee\
+            13:creation datei1700000000e4:info".to_vec();
+        bytes.extend_from_slice(info);
+        bytes.extend_from_slice(b"e");
+        bytes
+    }
+
+    #[test]
+    fn test_metainfo() {
+        let bytes = torrent(INFO);
+        let m: Metainfo = decode(&bytes).unwrap();
+
+        assert_eq!(m.announce.as_deref(), Some("http://tracker"));
+        assert_eq!(m.announce_list, Some(vec![
+            vec!["http://tracker".to_owned()],
+            vec!["udp".to_owned()],
+        ]));
+        assert_eq!(m.creation_date, Some(1700000000));
+        assert_eq!(m.comment, None);
+
+        assert_eq!(m.info.name_str(), Some("foo"));
+        assert_eq!(m.info.piece_length, 16384);
+        assert_eq!(m.info.piece_hashes().collect::<Vec<_>>(), [&b"aaaaaaaaaaaaaaaaaaaa"[..]]);
+        assert!(m.info.is_private());
+        assert_eq!(m.info.total_length(), 8);
+        assert_eq!(m.info.extra[&b"source"[..]], Value::String("barz".to_owned()));
+
+        match m.info.files {
+            Some(Files::Multiple(ref files)) => {
+                assert_eq!(files.len(), 2);
+                assert_eq!(files[0].path_str(), Some(vec!["a", "b"]));
+            }
+            _ => panic!("expected multiple files"),
+        }
+
+        assert!(m.info.is_v1() && !m.info.is_v2());
+        assert_eq!(m.info_hash, HashOf(Sha1::digest(INFO)));
+        assert_eq!(m.info_hash_v2, None);
+        assert_eq!(m.info.hash().unwrap(), m.info_hash);
+        assert_eq!(encode(&m).unwrap(), bytes);
+
+        let m: Metainfo = decode_from(&bytes[..]).unwrap();
+        assert_eq!(m.info_hash, HashOf(Sha1::digest(INFO)));
+
+        let info = b"d6:lengthi7e4:name1:x12:piece lengthi1e6:pieces0:e";
+        let m: Metainfo = decode(&torrent(info)).unwrap();
+        assert_eq!(m.info.files, Some(Files::Single(7)));
+        assert!(!m.info.is_private());
+        assert_eq!(m.info_hash, HashOf(Sha1::digest(info)));
+
+        // `private` is written back as it was read
+        let info = b"d6:lengthi7e4:name1:x12:piece lengthi1e6:pieces0:7:privatei0ee";
+        let bytes = torrent(info);
+        let m: Metainfo = decode(&bytes).unwrap();
+        assert_eq!(m.info.private, Some(0));
+        assert!(!m.info.is_private());
+        assert_eq!(m.info.hash().unwrap(), HashOf(Sha1::digest(info)));
+        assert_eq!(encode(&m).unwrap(), bytes);
+
+        // Names and paths need not be UTF-8 encoded
+        let info = b"d5:filesld6:lengthi1e4:pathl1:a2:\xfe\xffeee\
+            4:name2:\xe9t12:piece lengthi1e6:pieces0:e";
+        let bytes = torrent(info);
+        let m: Metainfo = decode(&bytes).unwrap();
+        assert_eq!(m.info.name, ByteString(vec![0xe9, b't']));
+        assert_eq!(m.info.name_str(), None);
+        match m.info.files {
+            Some(Files::Multiple(ref files)) => {
+                assert_eq!(files[0].path, [ByteString::from("a"), ByteString(vec![0xfe, 0xff])]);
+                assert_eq!(files[0].path_str(), None);
+            }
+            _ => panic!("expected multiple files"),
+        }
+        assert_eq!(m.info_hash, HashOf(Sha1::digest(info)));
+        assert_eq!(encode(&m).unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_metainfo_v2() {
+        let mut bytes = b"d4:info".to_vec();
+        bytes.extend_from_slice(INFO_V2);
+        bytes.extend_from_slice(b"12:piece layersd32:rrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrr64:xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyyye");
+        bytes.extend_from_slice(b"e");
+
+        let m: Metainfo = decode(&bytes).unwrap();
+        assert!(m.info.is_v2() && !m.info.is_v1() && !m.info.is_hybrid());
+        assert_eq!(m.info.meta_version, Some(2));
+        assert_eq!(m.info.pieces, None);
+        assert_eq!(m.info.piece_hashes().count(), 0);
+        assert_eq!(m.info.total_length(), 5);
+
+        let tree = m.info.file_tree.as_ref().unwrap();
+        let files = tree.files();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].0, ["a", "b"]);
+        assert_eq!(files[0].1.pieces_root, None);
+        assert_eq!(files[1].0, ["foo"]);
+        assert_eq!(files[1].1.length, 5);
+        assert_eq!(files[1].1.pieces_root, Some([b'r'; 32]));
+
+        assert_eq!(m.info_hash, HashOf(Sha1::digest(INFO_V2)));
+        assert_eq!(m.info_hash_v2, Some(HashOf(Sha256::digest(INFO_V2))));
+        assert_eq!(m.info.hash_v2().unwrap(), HashOf(Sha256::digest(INFO_V2)));
+
+        let layer = m.piece_layer(&[b'r'; 32]).unwrap().collect::<Vec<_>>();
+        assert_eq!(layer, [&[b'x'; 32][..], &[b'y'; 32][..]]);
+        assert!(m.piece_layer(&[0; 32]).is_none());
+
+        assert_eq!(encode(&m).unwrap(), bytes);
+
+        let m: Metainfo = decode(&torrent(INFO_HYBRID)).unwrap();
+        assert!(m.info.is_hybrid());
+        assert_eq!(m.info.files, Some(Files::Single(5)));
+        assert_eq!(m.info.piece_hashes().count(), 1);
+        match m.info.file_tree {
+            Some(FileTree::Directory(ref entries)) => assert_eq!(entries.len(), 1),
+            _ => panic!("expected directory"),
+        }
+        assert_eq!(m.info_hash, HashOf(Sha1::digest(INFO_HYBRID)));
+        assert_eq!(m.info_hash_v2, Some(HashOf(Sha256::digest(INFO_HYBRID))));
+        assert_eq!(encode(&m).unwrap(), torrent(INFO_HYBRID));
+
+        // Of duplicate entries, the first is retained
+        let mut d = Decoder::new(b"d\
+            1:bd0:d6:lengthi1eee\
+            1:ad0:d6:lengthi2eee\
+            1:ad0:d6:lengthi3eee\
+            e");
+        d.set_options(DecoderOptions::new().lenient(true).duplicate_keys(DuplicateKeys::First));
+        let tree = FileTree::decode(&mut d).unwrap();
+        assert_eq!(d.warnings().len(), 2);
+        let files = tree.files();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].0, ["a"]);
+        assert_eq!(files[0].1.length, 2);
+    }
+
+    #[test]
+    fn test_metainfo_errors() {
+        let e = decode::<Metainfo>(&torrent(b"d4:name1:x12:piece lengthi1e6:pieces0:e"))
+            .unwrap_err();
+        assert_eq!(*e.kind(), DecodeErrorKind::MissingField("length".to_owned()));
+        assert_eq!(e.path(), &[PathSegment::Key("info".to_owned())]);
+
+        let e = decode::<Metainfo>(&torrent(b"d6:lengthi1e4:name1:x12:piece lengthi1e6:pieces1:ae"))
+            .unwrap_err();
+        assert_eq!(*e.kind(),
+            DecodeErrorKind::Custom("length of `pieces` is not a multiple of 20".to_owned()));
+
+        let e = decode::<Metainfo>(&torrent(b"d4:name1:x12:piece lengthi1ee")).unwrap_err();
+        assert_eq!(*e.kind(), DecodeErrorKind::MissingField("pieces".to_owned()));
+
+        let e = decode::<Metainfo>(&torrent(b"d9:file treede4:name1:x12:piece lengthi1ee"))
+            .unwrap_err();
+        assert_eq!(*e.kind(), DecodeErrorKind::MissingField("meta version".to_owned()));
+
+        let e = decode::<Metainfo>(&torrent(
+            b"d9:file treede12:meta versioni3e4:name1:x12:piece lengthi1ee")).unwrap_err();
+        assert_eq!(*e.kind(), DecodeErrorKind::Custom("unsupported meta version 3".to_owned()));
+
+        let e = decode::<FileTree>(b"d1:ad0:d6:lengthi1e11:pieces root1:xeee").unwrap_err();
+        assert_eq!(*e.kind(),
+            DecodeErrorKind::Custom("length of `pieces root` is not 32".to_owned()));
+        assert_eq!(e.path(), &[PathSegment::Key("a".to_owned()), PathSegment::Key("".to_owned())]);
+
+        let e = decode::<FileTree>(b"d0:d6:lengthi1ee1:ad0:d6:lengthi1eeee").unwrap_err();
+        assert_eq!(*e.kind(),
+            DecodeErrorKind::Custom("file tree entry is both a file and a directory".to_owned()));
+        assert_eq!(e.position(), Some(0));
+
+        let e = decode::<Metainfo>(b"d8:announce1:xe").unwrap_err();
+        assert_eq!(*e.kind(), DecodeErrorKind::MissingField("info".to_owned()));
+    }
+
+    /// Creates an empty directory for the files of a test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("bencode-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_builder() {
+        let dir = temp_dir("builder");
+        let root = dir.join("foo");
+        fs::create_dir_all(root.join("b")).unwrap();
+        fs::write(root.join("a"), b"abc").unwrap();
+        fs::write(root.join("b").join("c"), b"hello").unwrap();
+
+        let builder = MetainfoBuilder::new()
+            .piece_length(16384)
+            .announce("http://tracker")
+            .created_by("test")
+            .private(true);
+
+        let bytes = builder.build_bytes(&root).unwrap();
+        let m: Metainfo = decode(&bytes).unwrap();
+
+        assert_eq!(m.announce.as_deref(), Some("http://tracker"));
+        assert_eq!(m.created_by.as_deref(), Some("test"));
+        assert_eq!(m.info.name_str(), Some("foo"));
+        assert!(m.info.is_private());
+        assert!(!m.info.is_v2());
+        assert_eq!(m.info.pieces, Some(Sha1::digest(b"abchello").to_vec()));
+        assert_eq!(m.info.total_length(), 8);
+        match m.info.files {
+            Some(Files::Multiple(ref files)) => {
+                assert_eq!(files.len(), 2);
+                assert_eq!(files[1].path_str(), Some(vec!["b", "c"]));
+            }
+            _ => panic!("expected multiple files"),
+        }
+        assert_eq!(m, builder.build(&root).unwrap());
+
+        let m = builder.clone().version(Version::Hybrid).build(&root).unwrap();
+        assert!(m.info.is_hybrid());
+        let mut piece = b"abc".to_vec();
+        piece.resize(16384, 0);
+        let mut pieces = Sha1::digest(&piece).to_vec();
+        pieces.extend_from_slice(&Sha1::digest(b"hello"));
+        assert_eq!(m.info.pieces, Some(pieces));
+        match m.info.files {
+            Some(Files::Multiple(ref files)) => {
+                assert_eq!(files.len(), 3);
+                assert_eq!(files[1].path_str(), Some(vec![".pad", "16381"]));
+                assert_eq!(files[1].length, 16381);
+                assert_eq!(files[1].extra[&b"attr"[..]], Value::String("p".to_owned()));
+            }
+            _ => panic!("expected multiple files"),
+        }
+        let files = m.info.file_tree.as_ref().unwrap().files();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[1].0, ["b", "c"]);
+        assert_eq!(files[1].1.pieces_root, Some(Sha256::digest(b"hello").into()));
+        assert_eq!(m.info_hash_v2, Some(m.info.hash_v2().unwrap()));
+
+        // A file of three blocks, spanning two pieces
+        let data = (0..40000).map(|i| i as u8).collect::<Vec<_>>();
+        let path = dir.join("data");
+        fs::write(&path, &data).unwrap();
+
+        let m = MetainfoBuilder::new().version(Version::V2).piece_length(32768)
+            .build(&path).unwrap();
+        assert_eq!(m.info.name_str(), Some("data"));
+        assert!(m.info.is_v2() && !m.info.is_v1());
+
+        let leaf = |b: &[u8]| Sha256::digest(b);
+        let node = |a: &[u8], b: &[u8]| Sha256::new().chain_update(a).chain_update(b).finalize();
+        let left = node(&leaf(&data[..16384]), &leaf(&data[16384..32768]));
+        let right = node(&leaf(&data[32768..]), &[0; 32]);
+        let root = node(&left, &right);
+
+        let files = m.info.file_tree.as_ref().unwrap().files();
+        assert_eq!(files[0].0, ["data"]);
+        assert_eq!(files[0].1.pieces_root, Some(root.into()));
+        assert_eq!(m.piece_layer(&root).unwrap().collect::<Vec<_>>(), [&left[..], &right[..]]);
+
+        let e = MetainfoBuilder::new().version(Version::V2).piece_length(1000)
+            .build(&path).unwrap_err();
+        assert!(matches!(e, BuildError::InvalidPieceLength(1000)));
+
+        fs::create_dir_all(dir.join("empty")).unwrap();
+        let e = MetainfoBuilder::new().build(dir.join("empty")).unwrap_err();
+        assert!(matches!(e, BuildError::NoFiles));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_builder_symlink() {
+        use std::os::unix::fs::symlink;
+
+        let dir = temp_dir("builder-symlink");
+        let root = dir.join("foo");
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::write(root.join("a/b"), b"abc").unwrap();
+        symlink("..", root.join("a/loop")).unwrap();
+
+        let e = MetainfoBuilder::new().build(&root).unwrap_err();
+        assert!(matches!(e, BuildError::Symlink(ref p) if *p == root.join("a/loop")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_builder_non_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = temp_dir("builder-non-utf8");
+        let root = dir.join("foo");
+        let file = root.join(OsStr::from_bytes(b"a\xff"));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(&file, b"abc").unwrap();
+
+        let m = MetainfoBuilder::new().build(&root).unwrap();
+        match m.info.files {
+            Some(Files::Multiple(ref files)) => {
+                assert_eq!(files[0].path, [ByteString::from(&b"a\xff"[..])]);
+                assert_eq!(files[0].path_str(), None);
+            }
+            _ => panic!("expected multiple files"),
+        }
+        let m2: Metainfo = decode(&encode(&m).unwrap()).unwrap();
+        assert_eq!(m2, m);
+
+        let e = MetainfoBuilder::new().version(Version::Hybrid).build(&root).unwrap_err();
+        assert!(matches!(e, BuildError::InvalidName(ref p) if *p == file));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,465 @@
+//! Serialization of `serde` values into the bencode format.
+//!
+//! Maps and structs are written as dicts with keys sorted in
+//! lexicographical order, regardless of the order in which the
+//! `Serialize` implementation produces them.
+//!
+//! `None` values are omitted from maps and structs. `None` values elsewhere,
+//! floating point numbers, and unit values cannot be represented
+//! and produce an error.
+
+use std::fmt::Display;
+use std::io::Write;
+
+use serde::ser::{self, Serialize};
+
+use {Decoder, Encoder, EncodeError};
+
+/// Serializes a value into a stream of bytes.
+pub fn to_bytes<T: ?Sized + Serialize>(t: &T) -> Result<Vec<u8>, EncodeError> {
+    let mut s = Serializer::new(Encoder::new());
+    t.serialize(&mut s)?;
+    Ok(s.into_inner().into_bytes().expect("encoding to Vec"))
+}
+
+/// Serializes a value into a writer.
+pub fn to_writer<T: ?Sized + Serialize, W: Write>(w: W, t: &T)
+        -> Result<(), EncodeError> {
+    let mut s = Serializer::new(Encoder::from_writer(w));
+    t.serialize(&mut s)?;
+    s.into_inner().flush()
+}
+
+/// Serializes `serde` values using an `Encoder`.
+pub struct Serializer<'a> {
+    encoder: Encoder<'a>,
+    /// Whether the value is a dict entry, which is omitted if `None`
+    entry: bool,
+}
+
+impl<'a> Serializer<'a> {
+    /// Constructs a new `Serializer`, writing to the given `Encoder`.
+    pub fn new(encoder: Encoder<'a>) -> Serializer<'a> {
+        Serializer{encoder, entry: false}
+    }
+
+    /// Consumes the `Serializer` and returns the inner `Encoder`.
+    pub fn into_inner(self) -> Encoder<'a> {
+        self.encoder
+    }
+}
+
+impl ser::Error for EncodeError {
+    fn custom<T: Display>(msg: T) -> EncodeError {
+        EncodeError::Custom(msg.to_string())
+    }
+}
+
+impl<'s, 'a> ser::Serializer for &'s mut Serializer<'a> {
+    type Ok = ();
+    type Error = EncodeError;
+
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = DictSerializer<'s, 'a>;
+    type SerializeStruct = DictSerializer<'s, 'a>;
+    type SerializeStructVariant = DictSerializer<'s, 'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), EncodeError> {
+        self.encoder.write_integer(v as u8)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), EncodeError> {
+        self.encoder.write_integer(v)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), EncodeError> {
+        self.encoder.write_integer(v)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), EncodeError> {
+        self.encoder.write_integer(v)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), EncodeError> {
+        self.encoder.write_integer(v)
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<(), EncodeError> {
+        self.encoder.write_integer(v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), EncodeError> {
+        self.encoder.write_integer(v)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), EncodeError> {
+        self.encoder.write_integer(v)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), EncodeError> {
+        self.encoder.write_integer(v)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), EncodeError> {
+        self.encoder.write_integer(v)
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<(), EncodeError> {
+        self.encoder.write_integer(v)
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<(), EncodeError> {
+        Err(EncodeError::Unsupported("f32"))
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<(), EncodeError> {
+        Err(EncodeError::Unsupported("f64"))
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), EncodeError> {
+        self.encoder.write_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), EncodeError> {
+        self.encoder.write_str(v)
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), EncodeError> {
+        self.encoder.write_bytes(v)
+    }
+
+    fn serialize_none(self) -> Result<(), EncodeError> {
+        if self.entry {
+            Ok(())
+        } else {
+            Err(EncodeError::Unsupported("None"))
+        }
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<(), EncodeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), EncodeError> {
+        Err(EncodeError::Unsupported("()"))
+    }
+
+    fn serialize_unit_struct(self, name: &'static str) -> Result<(), EncodeError> {
+        Err(EncodeError::Unsupported(name))
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32,
+            variant: &'static str) -> Result<(), EncodeError> {
+        self.encoder.write_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(self, _name: &'static str,
+            value: &T) -> Result<(), EncodeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(self, _name: &'static str,
+            _index: u32, variant: &'static str, value: &T) -> Result<(), EncodeError> {
+        self.entry = false;
+        self.encoder.write_byte(b'd')?;
+        self.encoder.write_str(variant)?;
+        value.serialize(&mut *self)?;
+        self.encoder.write_byte(b'e')
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self, EncodeError> {
+        self.entry = false;
+        self.encoder.write_byte(b'l')?;
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self, EncodeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize)
+            -> Result<Self, EncodeError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, _index: u32,
+            variant: &'static str, _len: usize) -> Result<Self, EncodeError> {
+        self.entry = false;
+        self.encoder.write_byte(b'd')?;
+        self.encoder.write_str(variant)?;
+        self.encoder.write_byte(b'l')?;
+        Ok(self)
+    }
+
+    fn serialize_map(self, _len: Option<usize>)
+            -> Result<DictSerializer<'s, 'a>, EncodeError> {
+        Ok(DictSerializer::new(self, false))
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize)
+            -> Result<DictSerializer<'s, 'a>, EncodeError> {
+        Ok(DictSerializer::new(self, false))
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, _index: u32,
+            variant: &'static str, _len: usize)
+            -> Result<DictSerializer<'s, 'a>, EncodeError> {
+        self.encoder.write_byte(b'd')?;
+        self.encoder.write_str(variant)?;
+        Ok(DictSerializer::new(self, true))
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+impl<'s, 'a> ser::SerializeSeq for &'s mut Serializer<'a> {
+    type Ok = ();
+    type Error = EncodeError;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T)
+            -> Result<(), EncodeError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), EncodeError> {
+        self.encoder.write_byte(b'e')
+    }
+}
+
+impl<'s, 'a> ser::SerializeTuple for &'s mut Serializer<'a> {
+    type Ok = ();
+    type Error = EncodeError;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T)
+            -> Result<(), EncodeError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), EncodeError> {
+        self.encoder.write_byte(b'e')
+    }
+}
+
+impl<'s, 'a> ser::SerializeTupleStruct for &'s mut Serializer<'a> {
+    type Ok = ();
+    type Error = EncodeError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T)
+            -> Result<(), EncodeError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), EncodeError> {
+        self.encoder.write_byte(b'e')
+    }
+}
+
+impl<'s, 'a> ser::SerializeTupleVariant for &'s mut Serializer<'a> {
+    type Ok = ();
+    type Error = EncodeError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T)
+            -> Result<(), EncodeError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), EncodeError> {
+        // Close both the list and the enclosing dict
+        self.encoder.write(b"ee")
+    }
+}
+
+/// Serializes a map or struct as a dict.
+///
+/// Entries are buffered and written in sorted order when complete.
+pub struct DictSerializer<'s, 'a: 's> {
+    ser: &'s mut Serializer<'a>,
+    /// Encoded key and value of each entry
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Key awaiting a value
+    key: Option<Vec<u8>>,
+    /// Whether the dict is enclosed in a variant dict
+    variant: bool,
+}
+
+impl<'s, 'a> DictSerializer<'s, 'a> {
+    fn new(ser: &'s mut Serializer<'a>, variant: bool) -> DictSerializer<'s, 'a> {
+        DictSerializer{
+            ser,
+            entries: Vec::new(),
+            key: None,
+            variant,
+        }
+    }
+
+    fn push_entry<T: ?Sized + Serialize>(&mut self, key: Vec<u8>, value: &T)
+            -> Result<(), EncodeError> {
+        let mut s = Serializer{encoder: Encoder::new(), entry: true};
+        value.serialize(&mut s)?;
+        let value = s.into_inner().into_bytes().expect("encoding to Vec");
+
+        // `None` produces no output and its field is omitted
+        if !value.is_empty() {
+            self.entries.push((key, value));
+        }
+
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), EncodeError> {
+        let mut entries = self.entries;
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        if entries.windows(2).any(|w| w[0].0 == w[1].0) {
+            return Err(EncodeError::Custom("duplicate key in map".to_owned()));
+        }
+
+        let e = &mut self.ser.encoder;
+
+        e.write_byte(b'd')?;
+        for (k, v) in entries {
+            e.write_bytes(&k)?;
+            e.write(&v)?;
+        }
+        e.write_byte(b'e')?;
+
+        if self.variant {
+            e.write_byte(b'e')?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Serializes a map key, which must produce a byte string.
+fn key_bytes<T: ?Sized + Serialize>(key: &T) -> Result<Vec<u8>, EncodeError> {
+    let buf = to_bytes(key)?;
+    let mut d = Decoder::new(&buf);
+
+    match d.read_bytes() {
+        Ok(ref b) if d.remaining() == 0 => Ok(b.clone()),
+        _ => Err(EncodeError::Custom("map key must be a string".to_owned()))
+    }
+}
+
+impl<'s, 'a> ser::SerializeMap for DictSerializer<'s, 'a> {
+    type Ok = ();
+    type Error = EncodeError;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T)
+            -> Result<(), EncodeError> {
+        self.key = Some(key_bytes(key)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T)
+            -> Result<(), EncodeError> {
+        let key = self.key.take()
+            .ok_or_else(|| EncodeError::Custom("map value without key".to_owned()))?;
+        self.push_entry(key, value)
+    }
+
+    fn end(self) -> Result<(), EncodeError> {
+        self.finish()
+    }
+}
+
+impl<'s, 'a> ser::SerializeStruct for DictSerializer<'s, 'a> {
+    type Ok = ();
+    type Error = EncodeError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T)
+            -> Result<(), EncodeError> {
+        self.push_entry(key.as_bytes().to_vec(), value)
+    }
+
+    fn end(self) -> Result<(), EncodeError> {
+        self.finish()
+    }
+}
+
+impl<'s, 'a> ser::SerializeStructVariant for DictSerializer<'s, 'a> {
+    type Ok = ();
+    type Error = EncodeError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T)
+            -> Result<(), EncodeError> {
+        self.push_entry(key.as_bytes().to_vec(), value)
+    }
+
+    fn end(self) -> Result<(), EncodeError> {
+        self.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use super::to_bytes;
+    use EncodeError;
+
+    #[derive(Serialize)]
+    struct Torrent {
+        name: String,
+        length: u64,
+        comment: Option<String>,
+        #[serde(rename = "announce-list")]
+        announce_list: Vec<Vec<String>>,
+    }
+
+    #[derive(Serialize)]
+    enum Message {
+        Ping,
+        Have(u32),
+        Request{index: u32, begin: u32},
+    }
+
+    #[test]
+    fn test_serialize() {
+        let t = Torrent{
+            name: "foo".to_owned(),
+            length: 123,
+            comment: None,
+            announce_list: vec![vec!["a".to_owned(), "b".to_owned()]],
+        };
+
+        assert_eq!(to_bytes(&t).unwrap(), &b"d\
+            13:announce-listll1:a1:bee\
+            6:lengthi123e\
+            4:name3:foo\
+            e"[..]);
+
+        let mut m = HashMap::new();
+        m.insert("zz", 1);
+        m.insert("a", 2);
+        m.insert("m", 3);
+
+        assert_eq!(to_bytes(&m).unwrap(), &b"d1:ai2e1:mi3e2:zzi1ee"[..]);
+
+        assert_eq!(to_bytes(&Message::Ping).unwrap(), &b"4:Ping"[..]);
+        assert_eq!(to_bytes(&Message::Have(7)).unwrap(), &b"d4:Havei7ee"[..]);
+        assert_eq!(to_bytes(&Message::Request{index: 1, begin: 2}).unwrap(),
+            &b"d7:Requestd5:begini2e5:indexi1eee"[..]);
+
+        assert_eq!(to_bytes(&1.5f64), Err(EncodeError::Unsupported("f64")));
+
+        let mut m = HashMap::new();
+        m.insert(1, 2);
+        assert!(to_bytes(&m).is_err());
+
+        let mut m = HashMap::new();
+        m.insert("a", None);
+        m.insert("b", Some(vec![Some(1)]));
+        assert_eq!(to_bytes(&m).unwrap(), &b"d1:bli1eee"[..]);
+
+        assert_eq!(to_bytes(&None::<i32>), Err(EncodeError::Unsupported("None")));
+        assert_eq!(to_bytes(&vec![None, Some(1)]), Err(EncodeError::Unsupported("None")));
+        m.insert("c", Some(vec![None]));
+        assert_eq!(to_bytes(&m), Err(EncodeError::Unsupported("None")));
+    }
+}
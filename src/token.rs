@@ -0,0 +1,243 @@
+//! Reading bencode data as a stream of tokens.
+//!
+//! A `Tokenizer` reports the structure of a value as it is read,
+//! without constructing a `Value` or any other decoded type.
+
+use std::borrow::Cow;
+
+use {Decoder, DecodeError, DecodeErrorKind, DictKeys};
+
+/// Element of bencode data produced by a `Tokenizer`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Token<'a> {
+    /// Beginning of a dict; followed by alternating keys and values
+    DictStart,
+    /// Beginning of a list; followed by its elements
+    ListStart,
+    /// End of the innermost dict or list
+    End,
+    /// Key of a dict entry
+    Key(Cow<'a, [u8]>),
+    /// Integer value
+    Integer(i64),
+    /// Integer value outside the range of `i64`, as decimal digits
+    BigInteger(String),
+    /// Byte string value
+    Bytes(Cow<'a, [u8]>),
+}
+
+/// Reads a single value from a `Decoder` as a series of tokens.
+///
+/// Each token is yielded along with its position in the stream.
+/// Dict keys are checked for order, and the limits of the `Decoder`
+/// options are enforced, as when decoding a value.
+/// Iteration ends after the first error.
+pub struct Tokenizer<'a> {
+    decoder: Decoder<'a>,
+    stack: Vec<Container>,
+    done: bool,
+}
+
+/// Dict or list within which tokens are being read.
+enum Container {
+    List,
+    Dict{
+        /// Keys previously read
+        keys: DictKeys,
+        /// Whether a value is expected, rather than a key or end
+        value: bool,
+    },
+}
+
+impl<'a> Tokenizer<'a> {
+    /// Constructs a new `Tokenizer`, reading from the given `Decoder`.
+    pub fn new(decoder: Decoder<'a>) -> Tokenizer<'a> {
+        Tokenizer{
+            decoder,
+            stack: Vec::new(),
+            done: false,
+        }
+    }
+
+    /// Returns a reference to the inner `Decoder`.
+    pub fn decoder(&self) -> &Decoder<'a> {
+        &self.decoder
+    }
+
+    /// Consumes the `Tokenizer` and returns the inner `Decoder`.
+    pub fn into_inner(self) -> Decoder<'a> {
+        self.decoder
+    }
+
+    /// Returns the nesting depth of the current position.
+    pub fn depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// Reads the next token from the stream.
+    ///
+    /// Returns `Ok(None)` once a complete value has been read.
+    pub fn next_token(&mut self) -> Result<Option<(u64, Token<'a>)>, DecodeError> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let res = self.read_token();
+
+        match res {
+            Ok(_) if self.stack.is_empty() => self.done = true,
+            Err(_) => self.done = true,
+            _ => ()
+        }
+
+        res.map(Some)
+    }
+
+    fn read_token(&mut self) -> Result<(u64, Token<'a>), DecodeError> {
+        let pos = self.decoder.position();
+
+        let b = self.decoder.peek_byte()?;
+
+        match self.stack.last_mut() {
+            Some(&mut Container::Dict{ref mut value, ref mut keys}) if !*value => {
+                if b == b'e' {
+                    return self.end(pos);
+                }
+
+                self.decoder.count_element()?;
+                let key = self.decoder.read_bytes_cow()?;
+                keys.check(&mut self.decoder, &key, pos)?;
+                *value = true;
+                return Ok((pos, Token::Key(key)));
+            }
+            Some(&mut Container::Dict{ref mut value, ..}) => *value = false,
+            Some(&mut Container::List) => {
+                if b == b'e' {
+                    return self.end(pos);
+                }
+                self.decoder.count_element()?;
+            }
+            None => ()
+        }
+
+        let token = match b {
+            b'd' => {
+                self.decoder.read_byte()?;
+                self.decoder.enter()?;
+                self.stack.push(Container::Dict{keys: DictKeys::new(), value: false});
+                Token::DictStart
+            }
+            b'l' => {
+                self.decoder.read_byte()?;
+                self.decoder.enter()?;
+                self.stack.push(Container::List);
+                Token::ListStart
+            }
+            b'i' => {
+                let s = self.decoder.read_big_integer()?;
+                match s.parse() {
+                    Ok(i) => Token::Integer(i),
+                    Err(_) => Token::BigInteger(s)
+                }
+            }
+            b'0' ..= b'9' => Token::Bytes(self.decoder.read_bytes_cow()?),
+            b => return Err(DecodeError::new(DecodeErrorKind::InvalidByte(b)).at(pos))
+        };
+
+        Ok((pos, token))
+    }
+
+    /// Reads the `e` closing the innermost dict or list.
+    fn end(&mut self, pos: u64) -> Result<(u64, Token<'a>), DecodeError> {
+        self.decoder.read_byte()?;
+        self.decoder.leave();
+        self.stack.pop();
+        Ok((pos, Token::End))
+    }
+}
+
+impl<'a> Iterator for Tokenizer<'a> {
+    type Item = Result<(u64, Token<'a>), DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_token().transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::borrow::Cow;
+    use super::{Token, Tokenizer};
+    use {Decoder, DecoderOptions, DecodeErrorKind, DecodeWarningKind};
+
+    fn bytes<'a>(b: &'a [u8]) -> Cow<'a, [u8]> {
+        Cow::Borrowed(b)
+    }
+
+    #[test]
+    fn test_tokenizer() {
+        let data = b"d1:ai1e1:bl3:fooi-99999999999999999999eee";
+        let tokens = Tokenizer::new(Decoder::new(data))
+            .collect::<Result<Vec<_>, _>>().unwrap();
+
+        assert_eq!(tokens, [
+            (0, Token::DictStart),
+            (1, Token::Key(bytes(b"a"))),
+            (4, Token::Integer(1)),
+            (7, Token::Key(bytes(b"b"))),
+            (10, Token::ListStart),
+            (11, Token::Bytes(bytes(b"foo"))),
+            (16, Token::BigInteger("-99999999999999999999".to_owned())),
+            (39, Token::End),
+            (40, Token::End),
+        ]);
+
+        let mut t = Tokenizer::new(Decoder::new(b"i1ei2e"));
+        assert_eq!(t.next().unwrap().unwrap(), (0, Token::Integer(1)));
+        assert!(t.next().is_none());
+        assert_eq!(*t.into_inner().finish().unwrap_err().kind(),
+            DecodeErrorKind::ExtraneousData);
+
+        let mut d = Decoder::from_reader(&b"l1:xe"[..]);
+        d.set_max_depth(4);
+        let tokens = Tokenizer::new(d).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(tokens[1], (1, Token::Bytes(Cow::Owned(b"x".to_vec()))));
+    }
+
+    #[test]
+    fn test_tokenizer_errors() {
+        fn error(data: &[u8]) -> (DecodeErrorKind, Option<u64>) {
+            let e = Tokenizer::new(Decoder::new(data))
+                .find_map(|r| r.err()).expect("error");
+            (e.kind().clone(), e.position())
+        }
+
+        assert_eq!(error(b"d1:bi1e1:ai2ee"), (DecodeErrorKind::InvalidDict, Some(7)));
+        assert_eq!(error(b"d1:ae"), (DecodeErrorKind::InvalidByte(b'e'), Some(4)));
+        assert_eq!(error(b"li1ex"), (DecodeErrorKind::InvalidByte(b'x'), Some(4)));
+        assert_eq!(error(b"l"), (DecodeErrorKind::Eof, Some(1)));
+
+        let mut d = Decoder::new(b"llle");
+        d.set_max_depth(2);
+        let e = Tokenizer::new(d).find_map(|r| r.err()).unwrap();
+        assert_eq!(*e.kind(), DecodeErrorKind::DepthLimitExceeded);
+        assert_eq!(e.position(), Some(2));
+
+        let mut d = Decoder::new(b"d1:bi1e1:ai2ee");
+        d.set_options(DecoderOptions::new().lenient(true));
+        let mut t = Tokenizer::new(d);
+        assert_eq!(t.by_ref().count(), 6);
+        assert_eq!(t.decoder().warnings().len(), 1);
+
+        let mut d = Decoder::new(b"d1:bi1e1:ai2e1:bi3ee");
+        d.set_options(DecoderOptions::new().lenient(true));
+        let mut t = Tokenizer::new(d);
+        assert_eq!(t.by_ref().count(), 8);
+        assert_eq!(t.decoder().warnings().iter()
+            .map(|w| (w.kind().clone(), w.position())).collect::<Vec<_>>(), [
+                (DecodeWarningKind::UnsortedKey("a".to_owned()), 7),
+                (DecodeWarningKind::DuplicateKey("b".to_owned()), 13),
+            ]);
+        assert_eq!(error(b"d1:bi1e1:ai2e1:bi3ee"), (DecodeErrorKind::InvalidDict, Some(7)));
+    }
+}